@@ -0,0 +1,91 @@
+//! Times the analysis functions on a long, busy game: ten skaters and a puck in every frame.
+//! Run with `cargo bench`.
+
+use nalgebra::{Point3, Vector3};
+use replay_parsing::hqm_game::{
+    HQMGameObject, HQMGameState, HQMPuck, HQMServerPlayer, HQMSkater, HQMTeam, DEFAULT_TICK_RATE,
+};
+use replay_parsing::hqm_rink::RinkGeometry;
+use replay_parsing::hqm_stats::{
+    detect_rushes, detect_scrums, detect_turnovers, excitement, faceoff_results, goalie_pulls,
+    possession_split, possession_streaks, scoring_chances, shot_map, DEFAULT_POSSESSION_THRESHOLD,
+};
+use replay_parsing::hqm_validate::plausibility_report;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+// Three periods of five minutes at 100 ticks per second
+const FRAMES: usize = 90000;
+const SKATERS: usize = 10;
+const RUNS: usize = 5;
+
+// Skaters skate circles around their own spot, the puck goes from stick to stick
+fn busy_game() -> Vec<HQMGameState> {
+    let rink = RinkGeometry::hqm_default();
+    let center = Point3::new(rink.width / 2.0, 0.0, rink.length / 2.0);
+    (0..FRAMES)
+        .map(|tick| {
+            let mut state = HQMGameState::empty();
+            state.period = 1 + (tick / 30000) as u32;
+            state.time = 30000 - (tick % 30000) as u32;
+            for i in 0..SKATERS {
+                let team = if i % 2 == 0 {
+                    HQMTeam::Red
+                } else {
+                    HQMTeam::Blue
+                };
+                state.player_list[i] = Some(HQMServerPlayer {
+                    name: format!("player {}", i),
+                    team_and_skater: Some((i + 1, team)),
+                });
+                let angle = tick as f32 / 200.0 + i as f32;
+                let spot =
+                    Point3::new(3.0 + 2.5 * i as f32, 0.0, center.z + (i as f32 - 4.5) * 4.0);
+                let pos = spot + Vector3::new(angle.cos(), 0.0, angle.sin()) * 2.0;
+                state.objects[i + 1] = HQMGameObject::Player(HQMSkater::at(pos));
+            }
+            let owner = (tick / 150) % SKATERS;
+            let puck = match &state.objects[owner + 1] {
+                HQMGameObject::Player(skater) => skater.stick_pos,
+                _ => center,
+            };
+            state.objects[0] = HQMGameObject::Puck(HQMPuck::at(puck));
+            state
+        })
+        .collect()
+}
+
+// The fastest of a few runs
+fn time<T>(name: &str, mut f: impl FnMut() -> T) {
+    let best = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            black_box(f());
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or(Duration::ZERO);
+    println!("{:<20} {:>8.2} ms", name, best.as_secs_f64() * 1000.0);
+}
+
+fn main() {
+    let frames = busy_game();
+    let rink = RinkGeometry::hqm_default();
+    let threshold = DEFAULT_POSSESSION_THRESHOLD;
+    println!("{} frames, {} skaters and a puck", frames.len(), SKATERS);
+    time("plausibility_report", || {
+        plausibility_report(&frames, DEFAULT_TICK_RATE)
+    });
+    time("possession_split", || possession_split(&frames, threshold));
+    time("possession_streaks", || {
+        possession_streaks(&frames, threshold)
+    });
+    time("excitement", || excitement(&frames));
+    time("detect_scrums", || detect_scrums(&frames, 3.0, 3));
+    time("detect_turnovers", || detect_turnovers(&frames, &rink));
+    time("detect_rushes", || detect_rushes(&frames, &rink));
+    time("scoring_chances", || scoring_chances(&frames, &rink));
+    time("goalie_pulls", || goalie_pulls(&frames, &rink));
+    time("shot_map", || shot_map(&frames, &rink));
+    time("faceoff_results", || faceoff_results(&frames, &rink));
+}
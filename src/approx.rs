@@ -0,0 +1,39 @@
+//! Approximate comparisons for decoded objects.
+//!
+//! Everything in a replay is quantized before it is sent. Positions are integers in units
+//! of 1/1024 m, so two decodings of the same value can only differ by a step or so.
+//! Rotation columns are sent as a recursive subdivision of an octant, 14 steps deep for
+//! skaters and pucks (31 bits) and 11 steps for sticks (25 bits), which gives an angular
+//! resolution of roughly 1e-4 and 1e-3 radians. Body turn and lean are steps of 1/8192 rad.
+
+use crate::hqm_game::{HQMPuck, HQMSkater};
+use nalgebra::{Matrix3, Point3};
+
+pub const POSITION_EPSILON: f32 = 2.0 / 1024.0;
+pub const ROTATION_EPSILON: f32 = 1e-3;
+pub const ANGLE_EPSILON: f32 = 2.0 / 8192.0;
+
+pub fn points_close(a: &Point3<f32>, b: &Point3<f32>, eps: f32) -> bool {
+    (a - b).norm() <= eps
+}
+
+/// Compares element by element.
+pub fn matrices_close(a: &Matrix3<f32>, b: &Matrix3<f32>, eps: f32) -> bool {
+    (a - b).amax() <= eps
+}
+
+/// Compares skaters using the default epsilons.
+pub fn skaters_close(a: &HQMSkater, b: &HQMSkater) -> bool {
+    points_close(&a.pos, &b.pos, POSITION_EPSILON)
+        && matrices_close(&a.rot, &b.rot, ROTATION_EPSILON)
+        && points_close(&a.stick_pos, &b.stick_pos, POSITION_EPSILON)
+        && matrices_close(&a.stick_rot, &b.stick_rot, ROTATION_EPSILON)
+        && (a.body_turn - b.body_turn).abs() <= ANGLE_EPSILON
+        && (a.body_lean - b.body_lean).abs() <= ANGLE_EPSILON
+}
+
+/// Compares pucks using the default epsilons.
+pub fn pucks_close(a: &HQMPuck, b: &HQMPuck) -> bool {
+    points_close(&a.pos, &b.pos, POSITION_EPSILON)
+        && matrices_close(&a.rot, &b.rot, ROTATION_EPSILON)
+}
@@ -0,0 +1,402 @@
+use crate::approx::{matrices_close, points_close};
+use crate::hqm_events::{goal_events, roster_table};
+use crate::hqm_game::{HQMGameObject, HQMGameState, HQMMessage, HQMServerPlayer, HQMTeam};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameMismatch {
+    pub frame: usize,
+    pub description: String,
+}
+
+impl fmt::Display for FrameMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame {}: {}", self.frame, self.description)
+    }
+}
+
+/// Compares two parsings frame by frame. Scores, clock and messages must match exactly,
+/// object geometry within `epsilon`.
+pub fn assert_frames_equivalent(
+    a: &[HQMGameState],
+    b: &[HQMGameState],
+    epsilon: f32,
+) -> Result<(), Vec<FrameMismatch>> {
+    let mut mismatches = vec![];
+    let mut mismatch =
+        |frame: usize, description: String| mismatches.push(FrameMismatch { frame, description });
+
+    if a.len() != b.len() {
+        mismatch(
+            a.len().min(b.len()),
+            format!("frame count differs: {} vs {}", a.len(), b.len()),
+        );
+    }
+
+    for (frame, (a, b)) in a.iter().zip(b.iter()).enumerate() {
+        if (a.red_score, a.blue_score) != (b.red_score, b.blue_score) {
+            mismatch(
+                frame,
+                format!(
+                    "score {}-{} vs {}-{}",
+                    a.red_score, a.blue_score, b.red_score, b.blue_score
+                ),
+            );
+        }
+        if (a.period, a.time) != (b.period, b.time) {
+            mismatch(
+                frame,
+                format!(
+                    "clock period {} time {} vs period {} time {}",
+                    a.period, a.time, b.period, b.time
+                ),
+            );
+        }
+        if a.messages_in_this_packet != b.messages_in_this_packet {
+            mismatch(
+                frame,
+                format!(
+                    "messages {:?} vs {:?}",
+                    a.messages_in_this_packet, b.messages_in_this_packet
+                ),
+            );
+        }
+        if a.objects.len() != b.objects.len() {
+            mismatch(
+                frame,
+                format!("object count {} vs {}", a.objects.len(), b.objects.len()),
+            );
+        }
+        for (slot, (a, b)) in a.objects.iter().zip(b.objects.iter()).enumerate() {
+            match (a, b) {
+                (HQMGameObject::None, HQMGameObject::None) => {}
+                (HQMGameObject::Puck(a), HQMGameObject::Puck(b)) => {
+                    if !points_close(&a.pos, &b.pos, epsilon) {
+                        mismatch(
+                            frame,
+                            format!("slot {} puck position {} vs {}", slot, a.pos, b.pos),
+                        );
+                    }
+                    if !matrices_close(&a.rot, &b.rot, epsilon) {
+                        mismatch(frame, format!("slot {} puck rotation differs", slot));
+                    }
+                }
+                (HQMGameObject::Player(a), HQMGameObject::Player(b)) => {
+                    if !points_close(&a.pos, &b.pos, epsilon) {
+                        mismatch(
+                            frame,
+                            format!("slot {} skater position {} vs {}", slot, a.pos, b.pos),
+                        );
+                    }
+                    if !matrices_close(&a.rot, &b.rot, epsilon) {
+                        mismatch(frame, format!("slot {} skater rotation differs", slot));
+                    }
+                    if !points_close(&a.stick_pos, &b.stick_pos, epsilon) {
+                        mismatch(
+                            frame,
+                            format!(
+                                "slot {} stick position {} vs {}",
+                                slot, a.stick_pos, b.stick_pos
+                            ),
+                        );
+                    }
+                    if !matrices_close(&a.stick_rot, &b.stick_rot, epsilon) {
+                        mismatch(frame, format!("slot {} stick rotation differs", slot));
+                    }
+                    if (a.body_turn - b.body_turn).abs() > epsilon
+                        || (a.body_lean - b.body_lean).abs() > epsilon
+                    {
+                        mismatch(
+                            frame,
+                            format!(
+                                "slot {} body turn/lean {}/{} vs {}/{}",
+                                slot, a.body_turn, a.body_lean, b.body_turn, b.body_lean
+                            ),
+                        );
+                    }
+                }
+                _ => mismatch(frame, format!("slot {} object type differs", slot)),
+            }
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches)
+    }
+}
+
+// Goals in two recordings this far apart on the game clock, in hundredths of a second,
+// are taken to be the same goal
+const GOAL_TIME_TOLERANCE: u32 = 100;
+
+/// What identifies a game independently of who recorded it: the goals on the game clock, the
+/// final score and everyone who was in the server. Two recordings of the same game have the
+/// same goals at the same clock readings even if the recorders joined or left at different
+/// times, while the roster and final score can differ a little for such partial recordings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameFingerprint {
+    // (period, clock, team) of every goal, in order
+    pub goals: Vec<(u32, u32, HQMTeam)>,
+    pub red_score: u32,
+    pub blue_score: u32,
+    // Player names, sorted and without duplicates
+    pub roster: Vec<String>,
+}
+
+pub fn fingerprint(frames: &[HQMGameState]) -> GameFingerprint {
+    let mut roster: Vec<String> = roster_table(frames).into_iter().map(|x| x.name).collect();
+    roster.sort();
+    roster.dedup();
+    let last = frames.last();
+    GameFingerprint {
+        goals: goal_events(frames)
+            .into_iter()
+            .map(|goal| (goal.period, goal.time, goal.team))
+            .collect(),
+        red_score: last.map_or(0, |x| x.red_score),
+        blue_score: last.map_or(0, |x| x.blue_score),
+        roster,
+    }
+}
+
+impl GameFingerprint {
+    /// From 0 to 1, how likely it is that both fingerprints come from the same game. Goals
+    /// weigh half: the share of goals that match in period and team within a second of game
+    /// clock. The roster overlap weighs 0.3 and an equal final score 0.2. Recordings of the
+    /// same game usually score above 0.8, different games with some of the same players
+    /// well below.
+    pub fn similarity(&self, other: &GameFingerprint) -> f32 {
+        let matching_goals = self
+            .goals
+            .iter()
+            .filter(|(period, time, team)| {
+                other.goals.iter().any(|(p, t, tm)| {
+                    p == period && tm == team && t.abs_diff(*time) <= GOAL_TIME_TOLERANCE
+                })
+            })
+            .count();
+        let goals = share(matching_goals, self.goals.len().max(other.goals.len()));
+        let common_players = self
+            .roster
+            .iter()
+            .filter(|name| other.roster.binary_search(name).is_ok())
+            .count();
+        let all_players = self.roster.len() + other.roster.len() - common_players;
+        let roster = share(common_players, all_players);
+        let score = if (self.red_score, self.blue_score) == (other.red_score, other.blue_score) {
+            1.0
+        } else {
+            0.0
+        };
+        0.5 * goals + 0.3 * roster + 0.2 * score
+    }
+}
+
+// part / total, and 1 if there is nothing to compare
+fn share(part: usize, total: usize) -> f32 {
+    if total == 0 {
+        1.0
+    } else {
+        part as f32 / total as f32
+    }
+}
+
+/// Replacements for parts of an original frame. Anything left empty is taken unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameEdit {
+    // (object slot, new object)
+    pub objects: Vec<(usize, HQMGameObject)>,
+    pub player_list: Option<Vec<Option<HQMServerPlayer>>>,
+    pub messages: Option<Vec<HQMMessage>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchOp {
+    // Original frames start..start + len as they are
+    Copy { start: usize, len: usize },
+    // An original frame with some parts replaced
+    Edit { source: usize, edit: FrameEdit },
+    // A frame that isn't in the original or changed too much to be an edit
+    Insert(Box<HQMGameState>),
+}
+
+/// The changes that turn one list of frames into another, made with `diff_replays`.
+/// Trimming only takes a few `Copy` ops, renaming players an `Edit` per frame with just the
+/// player list and messages.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReplayPatch {
+    pub ops: Vec<PatchOp>,
+}
+
+/// The patch turning `original` into `edited`. Edited frames are matched to original ones by
+/// packet number. A matched frame with the same scoreboard, clock, packet number and message
+/// window becomes an edit, anything else is stored whole.
+pub fn diff_replays(original: &[HQMGameState], edited: &[HQMGameState]) -> ReplayPatch {
+    let mut by_packet: HashMap<u32, usize> = HashMap::new();
+    for (i, frame) in original.iter().enumerate() {
+        by_packet.entry(frame.packet_number).or_insert(i);
+    }
+    let mut ops = vec![];
+    for frame in edited {
+        let source = by_packet.get(&frame.packet_number).copied();
+        let op = match source.map(|i| (i, &original[i])) {
+            Some((source, old)) if old == frame => {
+                if let Some(PatchOp::Copy { start, len }) = ops.last_mut() {
+                    if *start + *len == source {
+                        *len += 1;
+                        continue;
+                    }
+                }
+                PatchOp::Copy {
+                    start: source,
+                    len: 1,
+                }
+            }
+            Some((source, old)) if same_frame_header(old, frame) => PatchOp::Edit {
+                source,
+                edit: FrameEdit {
+                    objects: frame
+                        .objects
+                        .iter()
+                        .enumerate()
+                        .filter(|(slot, object)| old.objects[*slot] != **object)
+                        .map(|(slot, object)| (slot, object.clone()))
+                        .collect(),
+                    player_list: (old.player_list != frame.player_list)
+                        .then(|| frame.player_list.clone()),
+                    messages: (old.messages_in_this_packet != frame.messages_in_this_packet)
+                        .then(|| frame.messages_in_this_packet.clone()),
+                },
+            },
+            _ => PatchOp::Insert(Box::new(frame.clone())),
+        };
+        ops.push(op);
+    }
+    ReplayPatch { ops }
+}
+
+// Everything but the objects, players and messages is equal
+fn same_frame_header(a: &HQMGameState, b: &HQMGameState) -> bool {
+    a.packet_number == b.packet_number
+        && a.red_score == b.red_score
+        && a.blue_score == b.blue_score
+        && a.period == b.period
+        && a.game_over == b.game_over
+        && a.time == b.time
+        && a.goal_message_timer == b.goal_message_timer
+        && a.msg_pos == b.msg_pos
+        && a.message_num == b.message_num
+        && a.objects.len() == b.objects.len()
+}
+
+/// Rebuilds the edited frames from the original ones.
+/// Panics if the patch refers to frames `original` doesn't have, i.e. it was made from
+/// another replay.
+pub fn apply_patch(original: &[HQMGameState], patch: &ReplayPatch) -> Vec<HQMGameState> {
+    let mut res = vec![];
+    for op in patch.ops.iter() {
+        match op {
+            PatchOp::Copy { start, len } => {
+                res.extend_from_slice(&original[*start..*start + *len]);
+            }
+            PatchOp::Edit { source, edit } => {
+                let mut frame = original[*source].clone();
+                for (slot, object) in edit.objects.iter() {
+                    frame.objects[*slot] = object.clone();
+                }
+                if let Some(player_list) = &edit.player_list {
+                    frame.player_list = player_list.clone();
+                }
+                if let Some(messages) = &edit.messages {
+                    frame.messages_in_this_packet = messages.clone();
+                }
+                res.push(frame);
+            }
+            PatchOp::Insert(frame) => res.push((**frame).clone()),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_replay::parse_replay;
+    use crate::test_util::*;
+
+    // alice joins and scores while the puck slides along
+    fn original() -> Vec<HQMGameState> {
+        let mut packets = packets(6);
+        for (i, packet) in packets.iter_mut().enumerate() {
+            packet.objects = vec![puck_packet(10.0 + i as f32, 0.5, 20.0)];
+            packet.msg_pos = if i < 1 { 0 } else { 1 };
+            packet.red_score = if i < 3 { 0 } else { 1 };
+        }
+        packets[0].messages = vec![join(0, "alice", Some((1, HQMTeam::Red)))];
+        packets[3].messages = vec![goal(HQMTeam::Red, Some(0), None)];
+        packets[4].msg_pos = 2;
+        packets[5].msg_pos = 2;
+        parse_replay(&replay_bytes(&packets)).unwrap().states
+    }
+
+    #[test]
+    fn unchanged_replay_is_one_copy() {
+        let frames = original();
+        let patch = diff_replays(&frames, &frames);
+        assert_eq!(patch.ops, vec![PatchOp::Copy { start: 0, len: 6 }]);
+        assert_eq!(apply_patch(&frames, &patch), frames);
+    }
+
+    #[test]
+    fn trimmed_replay_round_trip() {
+        let frames = original();
+        let edited = frames[2..5].to_vec();
+        let patch = diff_replays(&frames, &edited);
+        assert_eq!(patch.ops, vec![PatchOp::Copy { start: 2, len: 3 }]);
+        assert_eq!(apply_patch(&frames, &patch), edited);
+    }
+
+    #[test]
+    fn renamed_player_round_trip() {
+        let frames = original();
+        let mut edited = frames.clone();
+        for frame in edited.iter_mut() {
+            frame.player_list[0].as_mut().unwrap().name = "player 1".to_string();
+        }
+        let patch = diff_replays(&frames, &edited);
+        assert_eq!(patch.ops.len(), 6);
+        for op in patch.ops.iter() {
+            let PatchOp::Edit { edit, .. } = op else {
+                panic!("expected an edit, got {:?}", op);
+            };
+            assert!(edit.objects.is_empty());
+            assert!(edit.player_list.is_some());
+            assert!(edit.messages.is_none());
+        }
+        assert_eq!(apply_patch(&frames, &patch), edited);
+    }
+
+    #[test]
+    fn moved_and_new_frames_round_trip() {
+        let frames = original();
+        let mut edited = frames.clone();
+        edited[1].objects[0] = frames[4].objects[0].clone();
+        let mut extra = frames[5].clone();
+        extra.packet_number = 6;
+        extra.time -= 1;
+        edited.push(extra);
+        let patch = diff_replays(&frames, &edited);
+        assert_eq!(patch.ops.len(), 4);
+        assert_eq!(patch.ops[0], PatchOp::Copy { start: 0, len: 1 });
+        assert!(
+            matches!(&patch.ops[1], PatchOp::Edit { source: 1, edit } if edit.objects.len() == 1)
+        );
+        assert_eq!(patch.ops[2], PatchOp::Copy { start: 2, len: 4 });
+        assert!(matches!(patch.ops[3], PatchOp::Insert(_)));
+        assert_eq!(apply_patch(&frames, &patch), edited);
+    }
+}
@@ -0,0 +1,610 @@
+use crate::hqm_game::{HQMGameState, HQMTeam};
+use crate::hqm_rink::RinkGeometry;
+use crate::hqm_stats::{puck_possession, DEFAULT_POSSESSION_THRESHOLD};
+use nalgebra::{Point3, Vector3};
+
+/// Something that scans consecutive ticks and produces events.
+/// `observe` is called once per tick in order, with the previous tick if there is one.
+pub trait EventDetector {
+    type Event;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState);
+
+    fn finish(self) -> Vec<Self::Event>
+    where
+        Self: Sized;
+}
+
+/// Object safe part of `EventDetector`, so detectors with different event types
+/// can be driven together by `run_detectors`.
+pub trait TickObserver {
+    fn observe_tick(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState);
+}
+
+impl<T: EventDetector> TickObserver for T {
+    fn observe_tick(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        self.observe(prev, cur)
+    }
+}
+
+/// Feeds all states to every detector in a single pass.
+/// Call `finish` on each detector afterwards to get its events.
+pub fn run_detectors(states: &[HQMGameState], detectors: &mut [&mut dyn TickObserver]) {
+    let mut prev = None;
+    for cur in states {
+        for detector in detectors.iter_mut() {
+            detector.observe_tick(prev, cur);
+        }
+        prev = Some(cur);
+    }
+}
+
+fn opponent(team: HQMTeam) -> HQMTeam {
+    match team {
+        HQMTeam::Red => HQMTeam::Blue,
+        HQMTeam::Blue => HQMTeam::Red,
+    }
+}
+
+fn horizontal(v: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(v.x, 0.0, v.z)
+}
+
+// Puck speed in meters per tick above which a released puck counts as a shot
+const SHOT_SPEED: f32 = 0.15;
+// Cosine of the largest angle between the puck's direction and the net for a shot
+const SHOT_DIRECTION_COS: f32 = 0.95;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shot {
+    pub tick: usize,
+    pub player: usize,
+    pub team: HQMTeam,
+    pub puck_pos: Point3<f32>,
+    // Puck speed right after the shot, in meters per tick
+    pub speed: f32,
+}
+
+/// A puck that suddenly moves fast towards the opposing net right after a player had it.
+/// Only the first puck is considered.
+pub struct ShotDetector {
+    rink: RinkGeometry,
+    tick: usize,
+    last_speed: f32,
+    shots: Vec<Shot>,
+}
+
+impl ShotDetector {
+    pub fn new(rink: RinkGeometry) -> Self {
+        ShotDetector {
+            rink,
+            tick: 0,
+            last_speed: 0.0,
+            shots: vec![],
+        }
+    }
+
+    fn detect(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) -> Option<Shot> {
+        let prev = prev?;
+        let (prev_puck, cur_puck) = (prev.puck()?, cur.puck()?);
+        let velocity = horizontal(cur_puck.pos - prev_puck.pos);
+        let speed = velocity.norm();
+        let was_slow = self.last_speed < SHOT_SPEED;
+        self.last_speed = speed;
+        if speed < SHOT_SPEED || !was_slow {
+            return None;
+        }
+        let (player, team) = puck_possession(prev, DEFAULT_POSSESSION_THRESHOLD)?;
+        let to_net = horizontal(self.rink.net_position(opponent(team)) - cur_puck.pos);
+        if velocity.dot(&to_net) < SHOT_DIRECTION_COS * speed * to_net.norm() {
+            return None;
+        }
+        Some(Shot {
+            tick: self.tick,
+            player,
+            team,
+            puck_pos: cur_puck.pos,
+            speed,
+        })
+    }
+}
+
+impl EventDetector for ShotDetector {
+    type Event = Shot;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        if let Some(shot) = self.detect(prev, cur) {
+            self.shots.push(shot);
+        }
+        self.tick += 1;
+    }
+
+    fn finish(self) -> Vec<Shot> {
+        self.shots
+    }
+}
+
+// Skaters closer than this, in meters, are in contact
+const CHECK_DISTANCE: f32 = 1.0;
+// Closing speed in meters per tick needed for contact to count as a check
+const CHECK_SPEED: f32 = 0.05;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub tick: usize,
+    pub hitter: usize,
+    pub target: usize,
+}
+
+/// Opposing skaters coming into contact at speed. The faster of the two is the hitter.
+#[derive(Default)]
+pub struct CheckDetector {
+    tick: usize,
+    checks: Vec<Check>,
+}
+
+impl CheckDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl EventDetector for CheckDetector {
+    type Event = Check;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        if let Some(prev) = prev {
+            let velocity = |player_index: usize, pos: &Point3<f32>| {
+                prev.skaters()
+                    .find(|(i, _, _)| *i == player_index)
+                    .map(|(_, _, skater)| pos - skater.pos)
+            };
+            for (a, a_team, a_skater) in cur.skaters() {
+                for (b, b_team, b_skater) in cur.skaters() {
+                    if a_team != HQMTeam::Red || b_team != HQMTeam::Blue {
+                        continue;
+                    }
+                    let (va, vb) = match (velocity(a, &a_skater.pos), velocity(b, &b_skater.pos)) {
+                        (Some(va), Some(vb)) => (va, vb),
+                        _ => continue,
+                    };
+                    let dist = (a_skater.pos - b_skater.pos).norm();
+                    let prev_dist = ((a_skater.pos - va) - (b_skater.pos - vb)).norm();
+                    // Only the tick where they first come into contact
+                    if dist > CHECK_DISTANCE || prev_dist <= CHECK_DISTANCE {
+                        continue;
+                    }
+                    if prev_dist - dist < CHECK_SPEED {
+                        continue;
+                    }
+                    let (hitter, target) = if va.norm() >= vb.norm() {
+                        (a, b)
+                    } else {
+                        (b, a)
+                    };
+                    self.checks.push(Check {
+                        tick: self.tick,
+                        hitter,
+                        target,
+                    });
+                }
+            }
+        }
+        self.tick += 1;
+    }
+
+    fn finish(self) -> Vec<Check> {
+        self.checks
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pass {
+    pub tick: usize,
+    pub from: usize,
+    pub to: usize,
+    pub team: HQMTeam,
+}
+
+/// Possession going from one player to a teammate, possibly with loose puck ticks in between.
+#[derive(Default)]
+pub struct PassDetector {
+    tick: usize,
+    last_owner: Option<(usize, HQMTeam)>,
+    passes: Vec<Pass>,
+}
+
+impl PassDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl EventDetector for PassDetector {
+    type Event = Pass;
+
+    fn observe(&mut self, _prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        if let Some((to, team)) = puck_possession(cur, DEFAULT_POSSESSION_THRESHOLD) {
+            if let Some((from, last_team)) = self.last_owner {
+                if from != to && last_team == team {
+                    self.passes.push(Pass {
+                        tick: self.tick,
+                        from,
+                        to,
+                        team,
+                    });
+                }
+            }
+            self.last_owner = Some((to, team));
+        }
+        self.tick += 1;
+    }
+
+    fn finish(self) -> Vec<Pass> {
+        self.passes
+    }
+}
+
+// A second shot within this many ticks of the first is a rebound
+const REBOUND_TICKS: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rebound {
+    pub shot: Shot,
+    pub original_shot_tick: usize,
+}
+
+/// Shots following another shot by the same team within a couple of seconds.
+pub struct ReboundDetector {
+    shots: ShotDetector,
+}
+
+impl ReboundDetector {
+    pub fn new(rink: RinkGeometry) -> Self {
+        ReboundDetector {
+            shots: ShotDetector::new(rink),
+        }
+    }
+}
+
+impl EventDetector for ReboundDetector {
+    type Event = Rebound;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        self.shots.observe(prev, cur)
+    }
+
+    fn finish(self) -> Vec<Rebound> {
+        let shots = self.shots.finish();
+        shots
+            .windows(2)
+            .filter(|pair| {
+                pair[0].team == pair[1].team && pair[1].tick - pair[0].tick <= REBOUND_TICKS
+            })
+            .map(|pair| Rebound {
+                shot: pair[1].clone(),
+                original_shot_tick: pair[0].tick,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stoppage {
+    pub start_tick: usize,
+    pub end_tick: usize,
+    pub period: u32,
+    pub time: u32,
+}
+
+/// Spans (inclusive) where the game clock is not running, e.g. after goals and between periods.
+#[derive(Default)]
+pub struct StoppageDetector {
+    tick: usize,
+    current: Option<Stoppage>,
+    stoppages: Vec<Stoppage>,
+}
+
+impl StoppageDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl EventDetector for StoppageDetector {
+    type Event = Stoppage;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        let stopped = prev.is_some_and(|prev| prev.time == cur.time && prev.period == cur.period);
+        if stopped {
+            let tick = self.tick;
+            self.current
+                .get_or_insert(Stoppage {
+                    start_tick: tick - 1,
+                    end_tick: tick,
+                    period: cur.period,
+                    time: cur.time,
+                })
+                .end_tick = tick;
+        } else if let Some(stoppage) = self.current.take() {
+            self.stoppages.push(stoppage);
+        }
+        self.tick += 1;
+    }
+
+    fn finish(mut self) -> Vec<Stoppage> {
+        if let Some(stoppage) = self.current.take() {
+            self.stoppages.push(stoppage);
+        }
+        self.stoppages
+    }
+}
+
+// A faceoff is won by the first team to touch the puck within this many ticks of the drop
+const FACEOFF_WIN_TICKS: usize = 300;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaceoffResult {
+    // Tick where the clock started running again
+    pub frame: usize,
+    pub location: Point3<f32>,
+    // Team defending the zone the faceoff was in, None for the neutral zone
+    pub zone: Option<HQMTeam>,
+    pub winner: Option<HQMTeam>,
+}
+
+/// Faceoffs, taken to be where the clock starts after a stoppage with a puck on the ice.
+/// The winner is the team that gets possession first, if anyone does soon enough.
+pub struct FaceoffDetector {
+    rink: RinkGeometry,
+    tick: usize,
+    was_stopped: bool,
+    current: Option<FaceoffResult>,
+    faceoffs: Vec<FaceoffResult>,
+}
+
+impl FaceoffDetector {
+    pub fn new(rink: RinkGeometry) -> Self {
+        FaceoffDetector {
+            rink,
+            tick: 0,
+            was_stopped: false,
+            current: None,
+            faceoffs: vec![],
+        }
+    }
+
+    fn zone(&self, pos: &Point3<f32>) -> Option<HQMTeam> {
+        [HQMTeam::Red, HQMTeam::Blue]
+            .into_iter()
+            .find(|team| self.rink.in_defending_zone(*team, pos))
+    }
+}
+
+impl EventDetector for FaceoffDetector {
+    type Event = FaceoffResult;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        if let Some(faceoff) = &mut self.current {
+            if let Some((_, team)) = puck_possession(cur, DEFAULT_POSSESSION_THRESHOLD) {
+                faceoff.winner = Some(team);
+            }
+            if faceoff.winner.is_some() || self.tick - faceoff.frame >= FACEOFF_WIN_TICKS {
+                self.faceoffs.extend(self.current.take());
+            }
+        }
+        let stopped = prev.is_some_and(|prev| prev.time == cur.time && prev.period == cur.period);
+        if self.was_stopped && !stopped && cur.period > 0 {
+            if let Some(puck) = cur.puck() {
+                self.faceoffs.extend(self.current.take());
+                self.current = Some(FaceoffResult {
+                    frame: self.tick,
+                    location: puck.pos,
+                    zone: self.zone(&puck.pos),
+                    winner: None,
+                });
+            }
+        }
+        self.was_stopped = stopped;
+        self.tick += 1;
+    }
+
+    fn finish(mut self) -> Vec<FaceoffResult> {
+        self.faceoffs.extend(self.current.take());
+        self.faceoffs
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Icing {
+    // Tick where the puck crossed the goal line
+    pub tick: usize,
+    pub release_tick: usize,
+    pub team: HQMTeam,
+    // The player who last touched the puck before it was iced
+    pub player: usize,
+}
+
+/// Likely icings: the puck is played from a team's own half and crosses the opposing goal line
+/// outside the net without anyone touching it on the way.
+///
+/// This is only an approximation. Touches are inferred from stick distance, so a puck passing
+/// near a stick without being played cancels the icing. The rule's exceptions, like the goalie
+/// being able to play the puck or a short-handed team, are not taken into account.
+pub struct IcingDetector {
+    rink: RinkGeometry,
+    touch_distance: f32,
+    tick: usize,
+    // Last touch as (player, team, tick, z of the puck), cleared once an icing has been emitted
+    last_touch: Option<(usize, HQMTeam, usize, f32)>,
+    icings: Vec<Icing>,
+}
+
+impl IcingDetector {
+    pub fn new(rink: RinkGeometry) -> Self {
+        IcingDetector {
+            rink,
+            touch_distance: DEFAULT_POSSESSION_THRESHOLD,
+            tick: 0,
+            last_touch: None,
+            icings: vec![],
+        }
+    }
+
+    /// How close a stick has to be to the puck to count as touching it.
+    pub fn touch_distance(mut self, touch_distance: f32) -> Self {
+        self.touch_distance = touch_distance;
+        self
+    }
+
+    fn own_half(&self, team: HQMTeam, z: f32) -> bool {
+        let center = self.rink.length / 2.0;
+        match team {
+            HQMTeam::Red => z >= center,
+            HQMTeam::Blue => z <= center,
+        }
+    }
+}
+
+impl EventDetector for IcingDetector {
+    type Event = Icing;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        let tick = self.tick;
+        self.tick += 1;
+        let puck = match cur.puck() {
+            Some(puck) => puck,
+            None => return,
+        };
+        if let Some((player, team)) = puck_possession(cur, self.touch_distance) {
+            self.last_touch = Some((player, team, tick, puck.pos.z));
+            return;
+        }
+        let prev_puck = match prev.and_then(|prev| prev.puck()) {
+            Some(prev_puck) => prev_puck,
+            None => return,
+        };
+        if let Some((player, team, release_tick, release_z)) = self.last_touch {
+            let goal_line = self.rink.goal_line_z(opponent(team));
+            let crossed = match team {
+                HQMTeam::Red => prev_puck.pos.z >= goal_line && puck.pos.z < goal_line,
+                HQMTeam::Blue => prev_puck.pos.z <= goal_line && puck.pos.z > goal_line,
+            };
+            let on_net = (puck.pos.x - self.rink.width / 2.0).abs() <= self.rink.net_width / 2.0;
+            if crossed && !on_net && self.own_half(team, release_z) {
+                self.icings.push(Icing {
+                    tick,
+                    release_tick,
+                    team,
+                    player,
+                });
+                self.last_touch = None;
+            }
+        }
+    }
+
+    fn finish(self) -> Vec<Icing> {
+        self.icings
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Offside {
+    // Tick where the puck entered the zone
+    pub tick: usize,
+    pub team: HQMTeam,
+    // Attackers that were already in the zone
+    pub players: Vec<usize>,
+}
+
+/// Likely offsides: the puck enters the attacking zone while a player of the team that last
+/// had it is already past the blue line.
+///
+/// Skater positions are the center of the body, so `margin` meters of slack are allowed
+/// before a skater counts as over the line. Tagging up and delayed offside are not modelled,
+/// every zone entry with an attacker ahead of the puck is reported.
+pub struct OffsideDetector {
+    rink: RinkGeometry,
+    touch_distance: f32,
+    margin: f32,
+    tick: usize,
+    last_team: Option<HQMTeam>,
+    offsides: Vec<Offside>,
+}
+
+impl OffsideDetector {
+    pub fn new(rink: RinkGeometry) -> Self {
+        OffsideDetector {
+            rink,
+            touch_distance: DEFAULT_POSSESSION_THRESHOLD,
+            margin: 0.5,
+            tick: 0,
+            last_team: None,
+            offsides: vec![],
+        }
+    }
+
+    /// How close a stick has to be to the puck to count as having it.
+    pub fn touch_distance(mut self, touch_distance: f32) -> Self {
+        self.touch_distance = touch_distance;
+        self
+    }
+
+    /// How far past the blue line a skater has to be to count as offside.
+    pub fn margin(mut self, margin: f32) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    // Distance past the blue line into the zone `team` attacks, negative if not past it
+    fn depth_in_zone(&self, team: HQMTeam, pos: &Point3<f32>) -> f32 {
+        let line = self.rink.blue_line_z(opponent(team));
+        match team {
+            HQMTeam::Red => line - pos.z,
+            HQMTeam::Blue => pos.z - line,
+        }
+    }
+}
+
+impl EventDetector for OffsideDetector {
+    type Event = Offside;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        let tick = self.tick;
+        self.tick += 1;
+        if let Some((_, team)) = puck_possession(cur, self.touch_distance) {
+            self.last_team = Some(team);
+        }
+        let (prev, team) = match (prev, self.last_team) {
+            (Some(prev), Some(team)) => (prev, team),
+            _ => return,
+        };
+        let (prev_puck, puck) = match (prev.puck(), cur.puck()) {
+            (Some(prev_puck), Some(puck)) => (prev_puck, puck),
+            _ => return,
+        };
+        let entered = self.depth_in_zone(team, &prev_puck.pos) < 0.0
+            && self.depth_in_zone(team, &puck.pos) >= 0.0;
+        if !entered {
+            return;
+        }
+        let players: Vec<usize> = prev
+            .skaters()
+            .filter(|(_, skater_team, skater)| {
+                *skater_team == team && self.depth_in_zone(team, &skater.pos) > self.margin
+            })
+            .map(|(player_index, _, _)| player_index)
+            .collect();
+        if !players.is_empty() {
+            self.offsides.push(Offside {
+                tick,
+                team,
+                players,
+            });
+        }
+    }
+
+    fn finish(self) -> Vec<Offside> {
+        self.offsides
+    }
+}
@@ -0,0 +1,589 @@
+use crate::hqm_game::{HQMGameState, HQMMessage, HQMTeam};
+use crate::hqm_rink::RinkGeometry;
+use crate::hqm_stats::{empty_net, puck_possession, DEFAULT_POSSESSION_THRESHOLD};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GoalEvent {
+    pub tick: usize,
+    pub period: u32,
+    pub time: u32,
+    pub team: HQMTeam,
+    pub goal_player_index: Option<usize>,
+    pub goal_player_name: Option<String>,
+    pub assist_player_index: Option<usize>,
+    pub assist_player_name: Option<String>,
+    // Score after the goal
+    pub red_score: u32,
+    pub blue_score: u32,
+    // The last goal scored by the team that won
+    pub is_game_winner: bool,
+    // Last player near the puck before the goal, from the possession tracker
+    pub last_touch: Option<usize>,
+    // The last touch was by a player of the team that conceded
+    pub is_own_goal: bool,
+    // Nobody from the conceding team was near its own net
+    pub empty_net: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatLine {
+    pub tick: usize,
+    pub period: u32,
+    pub time: u32,
+    pub player_index: Option<usize>,
+    pub player_name: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeriodChange {
+    pub tick: usize,
+    pub period: u32,
+    pub time: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoreChange {
+    pub tick: usize,
+    pub period: u32,
+    pub time: u32,
+    pub red_score: u32,
+    pub blue_score: u32,
+}
+
+pub(crate) fn player_name(state: &HQMGameState, player_index: usize) -> Option<String> {
+    let p = state.player_list.get(player_index)?.as_ref();
+    p.map(|p| p.name.clone())
+}
+
+/// All goals in the replay. Marking the game winner, the last goal of the winning team, needs
+/// the final score, so this always looks at the whole replay even if only early goals are of
+/// interest.
+pub fn goal_events(states: &[HQMGameState]) -> Vec<GoalEvent> {
+    goal_events_with(states, &RinkGeometry::default())
+}
+
+/// Like `goal_events`, with the rink used to tell empty nets and where the goal line is.
+pub fn goal_events_with(states: &[HQMGameState], rink: &RinkGeometry) -> Vec<GoalEvent> {
+    let mut res = vec![];
+    for (tick, state) in states.iter().enumerate() {
+        for msg in state.messages_in_this_packet.iter() {
+            if let HQMMessage::Goal {
+                team,
+                goal_player_index,
+                assist_player_index,
+            } = *msg
+            {
+                res.push(GoalEvent {
+                    tick,
+                    period: state.period,
+                    time: state.time,
+                    team,
+                    goal_player_index,
+                    goal_player_name: goal_player_index.and_then(|i| player_name(state, i)),
+                    assist_player_index,
+                    assist_player_name: assist_player_index.and_then(|i| player_name(state, i)),
+                    red_score: state.red_score,
+                    blue_score: state.blue_score,
+                    is_game_winner: false,
+                    last_touch: None,
+                    is_own_goal: false,
+                    empty_net: false,
+                });
+            }
+        }
+    }
+    if let Some(last) = states.last() {
+        mark_game_winner(&mut res, last.red_score, last.blue_score);
+    }
+    for goal in res.iter_mut() {
+        let conceding = match goal.team {
+            HQMTeam::Red => HQMTeam::Blue,
+            HQMTeam::Blue => HQMTeam::Red,
+        };
+        goal.empty_net = empty_net(&states[goal.tick], conceding, rink);
+        // By the goal tick the puck is in the net, often right at the goalie's stick, so only
+        // frames from before it crossed the goal line count
+        let last_touch = states[..goal.tick]
+            .iter()
+            .rev()
+            .filter(|state| {
+                !state
+                    .puck()
+                    .is_some_and(|puck| rink.behind_goal_line(conceding, &puck.pos))
+            })
+            .find_map(|state| puck_possession(state, DEFAULT_POSSESSION_THRESHOLD));
+        if let Some((player_index, team)) = last_touch {
+            goal.last_touch = Some(player_index);
+            goal.is_own_goal = team != goal.team;
+        }
+    }
+    res
+}
+
+/// The goal that put the winner ahead for good, with its tick to jump to. Unlike
+/// `GoalEvent::is_game_winner` this is not always the winner's last goal, later goals only
+/// extend the lead. `None` for tied games and for replays that end before the game is over.
+pub fn winning_goal(frames: &[HQMGameState]) -> Option<GoalEvent> {
+    let last = frames.last()?;
+    if !last.game_over {
+        return None;
+    }
+    let (team, loser_score) = winner(last.red_score, last.blue_score)?;
+    goal_events(frames).into_iter().find(|goal| {
+        let score = match team {
+            HQMTeam::Red => goal.red_score,
+            HQMTeam::Blue => goal.blue_score,
+        };
+        goal.team == team && score == loser_score + 1
+    })
+}
+
+/// Goals with at most `within_ticks` hundredths of a second left on the clock, e.g. 6000
+/// for the last minute. The clock counts down to 0 in every period, overtime included, so
+/// the time left is just the clock reading and the period's length doesn't matter.
+/// Warmup goals don't count for anything and are left out.
+pub fn late_goals(frames: &[HQMGameState], within_ticks: u32) -> Vec<GoalEvent> {
+    goal_events(frames)
+        .into_iter()
+        .filter(|goal| PeriodKind::of(goal.period) != PeriodKind::Warmup)
+        .filter(|goal| goal.time <= within_ticks)
+        .collect()
+}
+
+/// The frames around each goal for highlight clips, as (first frame index, frames):
+/// `pre_ticks` before the goal tick and `post_ticks` after it. Clips stop at the start and
+/// end of the replay and at period changes, so a goal right before an intermission doesn't
+/// pull in the next period.
+pub fn goal_clips(
+    frames: &[HQMGameState],
+    pre_ticks: usize,
+    post_ticks: usize,
+) -> Vec<(usize, Vec<HQMGameState>)> {
+    goal_events(frames)
+        .into_iter()
+        .map(|goal| {
+            let same_period = |i: &usize| frames[*i].period == goal.period;
+            let start = (goal.tick.saturating_sub(pre_ticks)..=goal.tick)
+                .find(same_period)
+                .unwrap_or(goal.tick);
+            let end = (goal.tick..=(goal.tick + post_ticks).min(frames.len() - 1))
+                .take_while(same_period)
+                .last()
+                .unwrap_or(goal.tick);
+            (start, frames[start..=end].to_vec())
+        })
+        .collect()
+}
+
+// The winning team and the loser's score, None for a tie
+fn winner(red_score: u32, blue_score: u32) -> Option<(HQMTeam, u32)> {
+    if red_score > blue_score {
+        Some((HQMTeam::Red, blue_score))
+    } else if blue_score > red_score {
+        Some((HQMTeam::Blue, red_score))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn mark_game_winner(goals: &mut [GoalEvent], red_score: u32, blue_score: u32) {
+    if let Some((team, _)) = winner(red_score, blue_score) {
+        if let Some(goal) = goals.iter_mut().rev().find(|goal| goal.team == team) {
+            goal.is_game_winner = true;
+        }
+    }
+}
+
+pub fn chat_lines(states: &[HQMGameState]) -> Vec<ChatLine> {
+    let mut res = vec![];
+    for (tick, state) in states.iter().enumerate() {
+        for msg in state.messages_in_this_packet.iter() {
+            if let HQMMessage::Chat {
+                player_index,
+                message,
+            } = msg
+            {
+                res.push(ChatLine {
+                    tick,
+                    period: state.period,
+                    time: state.time,
+                    player_index: *player_index,
+                    player_name: player_index.and_then(|i| player_name(state, i)),
+                    message: message.clone(),
+                });
+            }
+        }
+    }
+    res
+}
+
+pub fn period_changes(states: &[HQMGameState]) -> Vec<PeriodChange> {
+    let mut res = vec![];
+    let mut previous = None;
+    for (tick, state) in states.iter().enumerate() {
+        if previous != Some(state.period) {
+            res.push(PeriodChange {
+                tick,
+                period: state.period,
+                time: state.time,
+            });
+            previous = Some(state.period);
+        }
+    }
+    res
+}
+
+pub fn score_changes(states: &[HQMGameState]) -> Vec<ScoreChange> {
+    let mut res = vec![];
+    let mut previous = None;
+    for (tick, state) in states.iter().enumerate() {
+        let score = (state.red_score, state.blue_score);
+        if previous.is_some() && previous != Some(score) {
+            res.push(ScoreChange {
+                tick,
+                period: state.period,
+                time: state.time,
+                red_score: state.red_score,
+                blue_score: state.blue_score,
+            });
+        }
+        previous = Some(score);
+    }
+    res
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum PeriodKind {
+    Warmup,
+    Regulation,
+    Overtime,
+}
+
+impl PeriodKind {
+    // Period 0 is the warmup, 1 to 3 are regulation and anything after that is overtime.
+    // Shootouts are not distinguishable in the replay format.
+    pub fn of(period: u32) -> Self {
+        match period {
+            0 => PeriodKind::Warmup,
+            1..=3 => PeriodKind::Regulation,
+            _ => PeriodKind::Overtime,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeriodInfo {
+    pub number: u32,
+    pub kind: PeriodKind,
+    pub start_tick: usize,
+    pub end_tick: usize,
+    pub red_goals: u32,
+    pub blue_goals: u32,
+}
+
+/// Consecutive runs of ticks with the same period number. A replay that stops early
+/// simply has fewer entries.
+pub fn periods(states: &[HQMGameState]) -> Vec<PeriodInfo> {
+    let mut res: Vec<PeriodInfo> = vec![];
+    for (tick, state) in states.iter().enumerate() {
+        match res.last_mut() {
+            Some(current) if current.number == state.period => current.end_tick = tick,
+            _ => res.push(PeriodInfo {
+                number: state.period,
+                kind: PeriodKind::of(state.period),
+                start_tick: tick,
+                end_tick: tick,
+                red_goals: 0,
+                blue_goals: 0,
+            }),
+        }
+    }
+    for goal in goal_events(states) {
+        if let Some(period) = res
+            .iter_mut()
+            .find(|x| (x.start_tick..=x.end_tick).contains(&goal.tick))
+        {
+            match goal.team {
+                HQMTeam::Red => period.red_goals += 1,
+                HQMTeam::Blue => period.blue_goals += 1,
+            }
+        }
+    }
+    res
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerPresence {
+    pub tick: usize,
+    pub period: u32,
+    pub time: u32,
+    pub player_index: usize,
+    pub player_name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameSample {
+    pub tick: usize,
+    pub period: u32,
+    pub time: u32,
+    pub red_score: u32,
+    pub blue_score: u32,
+    pub puck: Option<[f32; 3]>,
+}
+
+/// One row of the flat event log. Every variant carries the tick it happened on.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum ReplayEvent {
+    FrameState(FrameSample),
+    Goal(GoalEvent),
+    Chat(ChatLine),
+    Join(PlayerPresence),
+    Leave(PlayerPresence),
+}
+
+impl ReplayEvent {
+    pub fn tick(&self) -> usize {
+        match self {
+            ReplayEvent::FrameState(x) => x.tick,
+            ReplayEvent::Goal(x) => x.tick,
+            ReplayEvent::Chat(x) => x.tick,
+            ReplayEvent::Join(x) | ReplayEvent::Leave(x) => x.tick,
+        }
+    }
+}
+
+/// Everything that happened in the replay as a single list sorted by tick, for bulk loading
+/// into a database. A frame state is included every `sample_interval` ticks, 0 leaves them out.
+pub fn to_event_log(frames: &[HQMGameState], sample_interval: usize) -> Vec<ReplayEvent> {
+    let mut res = vec![];
+    if sample_interval > 0 {
+        for (tick, frame) in frames.iter().enumerate().step_by(sample_interval) {
+            res.push(ReplayEvent::FrameState(FrameSample {
+                tick,
+                period: frame.period,
+                time: frame.time,
+                red_score: frame.red_score,
+                blue_score: frame.blue_score,
+                puck: frame
+                    .puck()
+                    .map(|puck| [puck.pos.x, puck.pos.y, puck.pos.z]),
+            }));
+        }
+    }
+    res.extend(goal_events(frames).into_iter().map(ReplayEvent::Goal));
+    res.extend(chat_lines(frames).into_iter().map(ReplayEvent::Chat));
+
+    let mut previous: Option<&HQMGameState> = None;
+    for (tick, frame) in frames.iter().enumerate() {
+        for player_index in 0..frame.player_list.len() {
+            let before = previous.and_then(|x| x.player_list[player_index].as_ref());
+            let after = frame.player_list[player_index].as_ref();
+            let presence = |name: &str| PlayerPresence {
+                tick,
+                period: frame.period,
+                time: frame.time,
+                player_index,
+                player_name: name.to_string(),
+            };
+            match (before, after) {
+                (None, Some(player)) => res.push(ReplayEvent::Join(presence(&player.name))),
+                (Some(player), None) => res.push(ReplayEvent::Leave(presence(&player.name))),
+                _ => {}
+            }
+        }
+        previous = Some(frame);
+    }
+
+    res.sort_by_key(|event| event.tick());
+    res
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RosterEntry {
+    pub name: String,
+    pub index: usize,
+    // Spans on each team as (team, start tick, end tick), end exclusive, in order. A player
+    // can switch teams several times while connected, spectating doesn't show up here.
+    pub team_history: Vec<(HQMTeam, usize, usize)>,
+}
+
+// Entry in the roster using a player index and the team span in progress
+type RosterSlot = (usize, Option<(HQMTeam, usize)>);
+
+/// Every player that was in the server and when they were on which team, from the
+/// `PlayerUpdate` messages. A player index reused by someone else gets a separate entry.
+/// Spans still open at the end of the replay end at `frames.len()`.
+///
+/// Some servers keep resending the same update. An update identical to the previous one for
+/// the same index, i.e. with the same name, connection state, team and object, is ignored,
+/// so it doesn't split a span or look like a rejoin.
+pub fn roster_table(frames: &[HQMGameState]) -> Vec<RosterEntry> {
+    let mut res: Vec<RosterEntry> = vec![];
+    // By player index
+    let mut current: Vec<Option<RosterSlot>> = vec![];
+    let mut previous: Vec<Option<&HQMMessage>> = vec![];
+    for (tick, frame) in frames.iter().enumerate() {
+        for msg in frame.messages_in_this_packet.iter() {
+            let HQMMessage::PlayerUpdate {
+                player_name,
+                object,
+                player_index,
+                in_server,
+            } = msg
+            else {
+                continue;
+            };
+            if current.len() <= *player_index {
+                current.resize(*player_index + 1, None);
+                previous.resize(*player_index + 1, None);
+            }
+            if previous[*player_index].replace(msg) == Some(msg) {
+                continue;
+            }
+            let slot = &mut current[*player_index];
+            let team = object.filter(|_| *in_server).map(|(_, team)| team);
+            let same_player = slot.is_some_and(|(entry, _)| res[entry].name == *player_name);
+            if let Some((entry, open)) = slot {
+                if let Some((open_team, start)) = *open {
+                    if Some(open_team) != team || !same_player {
+                        res[*entry].team_history.push((open_team, start, tick));
+                        *open = None;
+                    }
+                }
+            }
+            if !in_server {
+                *slot = None;
+                continue;
+            }
+            if !same_player {
+                res.push(RosterEntry {
+                    name: player_name.clone(),
+                    index: *player_index,
+                    team_history: vec![],
+                });
+                *slot = Some((res.len() - 1, None));
+            }
+            if let Some((_, open)) = slot {
+                if let (None, Some(team)) = (*open, team) {
+                    *open = Some((team, tick));
+                }
+            }
+        }
+    }
+    for (entry, open) in current.into_iter().flatten() {
+        if let Some((team, start)) = open {
+            res[entry].team_history.push((team, start, frames.len()));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::{HQMGameObject, HQMPuck, HQMServerPlayer, HQMSkater};
+    use nalgebra::Point3;
+
+    // One state per entry, with a goal message on the ticks where the score changes
+    fn game(scores: &[(u32, u32)]) -> Vec<HQMGameState> {
+        let mut res: Vec<HQMGameState> = vec![];
+        for &(red_score, blue_score) in scores {
+            let mut state = HQMGameState::empty();
+            state.period = 1;
+            state.red_score = red_score;
+            state.blue_score = blue_score;
+            if let Some(prev) = res.last() {
+                let team = if red_score > prev.red_score {
+                    Some(HQMTeam::Red)
+                } else if blue_score > prev.blue_score {
+                    Some(HQMTeam::Blue)
+                } else {
+                    None
+                };
+                if let Some(team) = team {
+                    state.messages_in_this_packet = vec![HQMMessage::Goal {
+                        team,
+                        goal_player_index: None,
+                        assist_player_index: None,
+                    }];
+                }
+            }
+            res.push(state);
+        }
+        res.last_mut().unwrap().game_over = true;
+        res
+    }
+
+    #[test]
+    fn game_winner_is_last_goal_of_winning_team() {
+        // Red takes the lead for good at 2-1 and adds another goal
+        let frames = game(&[(0, 0), (1, 0), (1, 1), (2, 1), (3, 1)]);
+        let goals = goal_events(&frames);
+        let winners: Vec<_> = goals.iter().filter(|x| x.is_game_winner).collect();
+        assert_eq!(winners.len(), 1);
+        assert_eq!(winners[0].tick, 4);
+        assert_eq!((winners[0].red_score, winners[0].blue_score), (3, 1));
+    }
+
+    #[test]
+    fn winning_goal_gives_the_lead_for_good() {
+        let frames = game(&[(0, 0), (1, 0), (1, 1), (2, 1), (3, 1)]);
+        let goal = winning_goal(&frames).unwrap();
+        assert_eq!(goal.tick, 3);
+        assert!(!goal.is_game_winner);
+    }
+
+    // Red shoots from the slot, the puck goes past the blue goalie into the net at low z
+    fn goal_past_goalie() -> Vec<HQMGameState> {
+        let mut frames = game(&[(0, 0), (0, 0), (0, 0), (1, 0)]);
+        for frame in frames.iter_mut() {
+            frame.player_list[0] = Some(HQMServerPlayer {
+                name: "red".to_string(),
+                team_and_skater: Some((1, HQMTeam::Red)),
+            });
+            frame.player_list[1] = Some(HQMServerPlayer {
+                name: "goalie".to_string(),
+                team_and_skater: Some((2, HQMTeam::Blue)),
+            });
+        }
+        let shooter = Point3::new(15.0, 0.5, 10.0);
+        let goalie = Point3::new(15.0, 0.5, 3.5);
+        for (frame, puck) in frames.iter_mut().zip([shooter, shooter, goalie, goalie]) {
+            frame.objects[0] = HQMGameObject::Puck(HQMPuck::at(puck));
+            frame.objects[1] = HQMGameObject::Player(HQMSkater::at(shooter));
+            frame.objects[2] = HQMGameObject::Player(HQMSkater::at(goalie));
+        }
+        frames
+    }
+
+    #[test]
+    fn last_touch_is_before_the_puck_crossed_the_line() {
+        let frames = goal_past_goalie();
+        let goals = goal_events(&frames);
+        assert_eq!(goals.len(), 1);
+        assert_eq!(goals[0].last_touch, Some(0));
+        assert!(!goals[0].is_own_goal);
+    }
+
+    #[test]
+    fn empty_net_uses_the_given_rink() {
+        let frames = goal_past_goalie();
+        assert!(!goal_events(&frames)[0].empty_net);
+        // With the goal lines further out the goalie is too far from the net
+        let rink = RinkGeometry {
+            goal_line_distance: 9.0,
+            ..RinkGeometry::hqm_default()
+        };
+        assert!(goal_events_with(&frames, &rink)[0].empty_net);
+    }
+
+    #[test]
+    fn no_game_winner_in_a_tie() {
+        let frames = game(&[(0, 0), (1, 0), (1, 1)]);
+        assert!(goal_events(&frames).iter().all(|x| !x.is_game_winner));
+        assert!(winning_goal(&frames).is_none());
+    }
+}
@@ -0,0 +1,678 @@
+use crate::hqm_events::{
+    chat_lines, goal_events, period_changes, player_name, score_changes, ChatLine, GoalEvent,
+    PeriodChange, ScoreChange,
+};
+use crate::hqm_game::{format_clock, HQMGameObject, HQMGameState, HQMTeam};
+use crate::hqm_rink::RinkGeometry;
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// Settings shared by the CSV and JSON exporters. The default writes floats at full precision.
+#[derive(Debug, Clone, Default)]
+pub struct ExportConfig {
+    // Decimal places to round positions and angles to, None to keep them as they are
+    pub(crate) precision: Option<usize>,
+}
+
+impl ExportConfig {
+    /// Positions are multiples of 1/1024 m, so 3 or 4 decimals still tell every value apart.
+    pub fn precision(mut self, precision: usize) -> Self {
+        self.precision = Some(precision);
+        self
+    }
+
+    fn round(&self, v: f32) -> f32 {
+        match self.precision {
+            Some(precision) => {
+                let scale = 10f64.powi(precision as i32);
+                ((v as f64 * scale).round() / scale) as f32
+            }
+            None => v,
+        }
+    }
+
+    fn round3(&self, v: [f32; 3]) -> [f32; 3] {
+        v.map(|x| self.round(x))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EventsExport {
+    pub goals: Vec<GoalEvent>,
+    pub chat: Vec<ChatLine>,
+    pub periods: Vec<PeriodChange>,
+    pub scores: Vec<ScoreChange>,
+}
+
+/// Goals, chat, period changes and score changes without any per-tick object data.
+pub fn export_events_json(states: &[HQMGameState]) -> serde_json::Result<String> {
+    let export = EventsExport {
+        goals: goal_events(states),
+        chat: chat_lines(states),
+        periods: period_changes(states),
+        scores: score_changes(states),
+    };
+    serde_json::to_string(&export)
+}
+
+/// One row per skater on the ice per tick.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerTickRow {
+    pub tick: usize,
+    pub period: u32,
+    pub time: u32,
+    pub player_index: usize,
+    pub player_name: String,
+    pub team: HQMTeam,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub stick_x: f32,
+    pub stick_y: f32,
+    pub stick_z: f32,
+    pub body_turn: f32,
+    pub body_lean: f32,
+}
+
+pub const PLAYER_COLUMNS: [&str; 14] = [
+    "tick",
+    "period",
+    "time",
+    "player_index",
+    "player_name",
+    "team",
+    "x",
+    "y",
+    "z",
+    "stick_x",
+    "stick_y",
+    "stick_z",
+    "body_turn",
+    "body_lean",
+];
+
+/// Joins each skater object with the player controlling it.
+pub fn player_rows(states: &[HQMGameState]) -> Vec<PlayerTickRow> {
+    let mut res = vec![];
+    for (tick, state) in states.iter().enumerate() {
+        for (player_index, team, skater) in state.skaters() {
+            res.push(PlayerTickRow {
+                tick,
+                period: state.period,
+                time: state.time,
+                player_index,
+                player_name: player_name(state, player_index).unwrap_or_default(),
+                team,
+                x: skater.pos.x,
+                y: skater.pos.y,
+                z: skater.pos.z,
+                stick_x: skater.stick_pos.x,
+                stick_y: skater.stick_pos.y,
+                stick_z: skater.stick_pos.z,
+                body_turn: skater.body_turn,
+                body_lean: skater.body_lean,
+            });
+        }
+    }
+    res
+}
+
+impl PlayerTickRow {
+    pub fn rounded(self, config: &ExportConfig) -> Self {
+        PlayerTickRow {
+            x: config.round(self.x),
+            y: config.round(self.y),
+            z: config.round(self.z),
+            stick_x: config.round(self.stick_x),
+            stick_y: config.round(self.stick_y),
+            stick_z: config.round(self.stick_z),
+            body_turn: config.round(self.body_turn),
+            body_lean: config.round(self.body_lean),
+            ..self
+        }
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn write_players_csv<W: Write>(states: &[HQMGameState], out: &mut W) -> io::Result<()> {
+    write_players_csv_with(states, out, &ExportConfig::default())
+}
+
+pub fn write_players_csv_with<W: Write>(
+    states: &[HQMGameState],
+    out: &mut W,
+    config: &ExportConfig,
+) -> io::Result<()> {
+    writeln!(out, "{}", PLAYER_COLUMNS.join(","))?;
+    for row in player_rows(states) {
+        let row = row.rounded(config);
+        writeln!(
+            out,
+            "{},{},{},{},{},{:?},{},{},{},{},{},{},{},{}",
+            row.tick,
+            row.period,
+            row.time,
+            row.player_index,
+            csv_field(&row.player_name),
+            row.team,
+            row.x,
+            row.y,
+            row.z,
+            row.stick_x,
+            row.stick_y,
+            row.stick_z,
+            row.body_turn,
+            row.body_lean
+        )?;
+    }
+    Ok(())
+}
+
+pub const GOAL_COLUMNS: [&str; 9] = [
+    "period",
+    "clock",
+    "team",
+    "scorer",
+    "assist",
+    "red_score",
+    "blue_score",
+    "empty_net",
+    "game_winner",
+];
+
+/// One row per goal. Scorer and assist are player names, empty if unknown.
+pub fn write_goals_csv<W: Write>(states: &[HQMGameState], out: &mut W) -> io::Result<()> {
+    writeln!(out, "{}", GOAL_COLUMNS.join(","))?;
+    for goal in goal_events(states) {
+        writeln!(
+            out,
+            "{},{},{:?},{},{},{},{},{},{}",
+            goal.period,
+            format_clock(goal.time),
+            goal.team,
+            csv_field(goal.goal_player_name.as_deref().unwrap_or_default()),
+            csv_field(goal.assist_player_name.as_deref().unwrap_or_default()),
+            goal.red_score,
+            goal.blue_score,
+            goal.empty_net,
+            goal.is_game_winner
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes the same table as `write_players_csv` as Parquet.
+#[cfg(feature = "parquet")]
+pub fn export_parquet<W: Write + Send>(
+    states: &[HQMGameState],
+    out: W,
+) -> Result<(), parquet::errors::ParquetError> {
+    use arrow_array::{ArrayRef, Float32Array, RecordBatch, StringArray, UInt32Array, UInt64Array};
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    let rows = player_rows(states);
+    let types = [
+        DataType::UInt64,
+        DataType::UInt32,
+        DataType::UInt32,
+        DataType::UInt32,
+        DataType::Utf8,
+        DataType::Utf8,
+        DataType::Float32,
+        DataType::Float32,
+        DataType::Float32,
+        DataType::Float32,
+        DataType::Float32,
+        DataType::Float32,
+        DataType::Float32,
+        DataType::Float32,
+    ];
+    let schema = Arc::new(Schema::new(
+        PLAYER_COLUMNS
+            .iter()
+            .zip(types)
+            .map(|(name, data_type)| Field::new(*name, data_type, false))
+            .collect::<Vec<_>>(),
+    ));
+
+    let floats = |f: fn(&PlayerTickRow) -> f32| -> ArrayRef {
+        Arc::new(Float32Array::from_iter_values(rows.iter().map(f)))
+    };
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|x| x.tick as u64),
+        )),
+        Arc::new(UInt32Array::from_iter_values(rows.iter().map(|x| x.period))),
+        Arc::new(UInt32Array::from_iter_values(rows.iter().map(|x| x.time))),
+        Arc::new(UInt32Array::from_iter_values(
+            rows.iter().map(|x| x.player_index as u32),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|x| x.player_name.as_str()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|x| format!("{:?}", x.team)),
+        )),
+        floats(|x| x.x),
+        floats(|x| x.y),
+        floats(|x| x.z),
+        floats(|x| x.stick_x),
+        floats(|x| x.stick_y),
+        floats(|x| x.stick_z),
+        floats(|x| x.body_turn),
+        floats(|x| x.body_lean),
+    ];
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let mut writer = ArrowWriter::try_new(out, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Features per object in `export_tensor`:
+/// position x, y, z in meters, the first two columns of the rotation matrix
+/// (enough to rebuild the whole matrix), and the velocity x, y, z in meters per tick.
+/// No normalization is applied, values are in the same units as the decoded states.
+pub const TENSOR_FEATURES: usize = 12;
+
+/// A dense `[ticks][objects][TENSOR_FEATURES]` array in row-major order, with a
+/// `[ticks][objects]` mask that is 1 where the object slot is in use. Unused slots are all zero.
+#[derive(Debug, Clone)]
+pub struct ReplayTensor {
+    pub ticks: usize,
+    pub objects: usize,
+    pub data: Vec<f32>,
+    pub mask: Vec<u8>,
+}
+
+/// Skaters and pucks alike, one row per object slot. The velocity is the position change
+/// from the previous tick, or zero if the slot was empty then.
+pub fn export_tensor(states: &[HQMGameState]) -> ReplayTensor {
+    let objects = states.iter().map(|x| x.objects.len()).max().unwrap_or(0);
+    let mut data = vec![0.0; states.len() * objects * TENSOR_FEATURES];
+    let mut mask = vec![0; states.len() * objects];
+    for (tick, state) in states.iter().enumerate() {
+        for (slot, object) in state.objects.iter().enumerate() {
+            let (pos, rot) = match object {
+                HQMGameObject::None => continue,
+                HQMGameObject::Player(skater) => (skater.pos, skater.rot),
+                HQMGameObject::Puck(puck) => (puck.pos, puck.rot),
+            };
+            let velocity = tick
+                .checked_sub(1)
+                .and_then(|prev| states[prev].objects.get(slot))
+                .and_then(|prev| prev.pos())
+                .map(|prev| pos - prev)
+                .unwrap_or_default();
+            let i = tick * objects + slot;
+            mask[i] = 1;
+            let row = &mut data[i * TENSOR_FEATURES..(i + 1) * TENSOR_FEATURES];
+            row[0..3].copy_from_slice(pos.coords.as_slice());
+            row[3..9].copy_from_slice(&rot.as_slice()[0..6]);
+            row[9..12].copy_from_slice(velocity.as_slice());
+        }
+    }
+    ReplayTensor {
+        ticks: states.len(),
+        objects,
+        data,
+        mask,
+    }
+}
+
+// Writes a version 1.0 .npy file, dtype is the numpy type string like "<f4"
+#[cfg(feature = "npy")]
+fn write_npy<W: Write>(out: &mut W, dtype: &str, shape: &[usize], bytes: &[u8]) -> io::Result<()> {
+    let shape: Vec<String> = shape.iter().map(|x| x.to_string()).collect();
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}",
+        dtype,
+        shape.join(", ")
+    );
+    // Magic, version and header length take 10 bytes, and the header ends in a newline,
+    // padded so that the data starts on a multiple of 64 bytes
+    let total = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - total % 64) % 64));
+    header.push('\n');
+    out.write_all(b"\x93NUMPY\x01\x00")?;
+    out.write_all(&(header.len() as u16).to_le_bytes())?;
+    out.write_all(header.as_bytes())?;
+    out.write_all(bytes)
+}
+
+#[cfg(feature = "npy")]
+impl ReplayTensor {
+    /// Writes the features as a float32 .npy file.
+    pub fn write_npy<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let bytes: Vec<u8> = self.data.iter().flat_map(|x| x.to_le_bytes()).collect();
+        write_npy(
+            out,
+            "<f4",
+            &[self.ticks, self.objects, TENSOR_FEATURES],
+            &bytes,
+        )
+    }
+
+    /// Writes the presence mask as a uint8 .npy file.
+    pub fn write_mask_npy<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write_npy(out, "|u1", &[self.ticks, self.objects], &self.mask)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkaterRecord {
+    pub player_index: usize,
+    pub player_name: String,
+    pub team: HQMTeam,
+    pub pos: [f32; 3],
+    pub stick_pos: [f32; 3],
+    pub body_turn: f32,
+    pub body_lean: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PuckRecord {
+    pub slot: usize,
+    pub pos: [f32; 3],
+}
+
+/// One frame as written by `frame_json`. Positions are [x, y, z] in meters.
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameRecord {
+    pub tick: usize,
+    pub packet_number: u32,
+    pub period: u32,
+    pub time: u32,
+    pub red_score: u32,
+    pub blue_score: u32,
+    pub game_over: bool,
+    pub skaters: Vec<SkaterRecord>,
+    pub pucks: Vec<PuckRecord>,
+}
+
+impl FrameRecord {
+    pub fn new(tick: usize, state: &HQMGameState) -> Self {
+        FrameRecord {
+            tick,
+            packet_number: state.packet_number,
+            period: state.period,
+            time: state.time,
+            red_score: state.red_score,
+            blue_score: state.blue_score,
+            game_over: state.game_over,
+            skaters: state
+                .skaters()
+                .map(|(player_index, team, skater)| SkaterRecord {
+                    player_index,
+                    player_name: player_name(state, player_index).unwrap_or_default(),
+                    team,
+                    pos: skater.pos.coords.into(),
+                    stick_pos: skater.stick_pos.coords.into(),
+                    body_turn: skater.body_turn,
+                    body_lean: skater.body_lean,
+                })
+                .collect(),
+            pucks: state
+                .pucks()
+                .map(|(slot, puck)| PuckRecord {
+                    slot,
+                    pos: puck.pos.coords.into(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn rounded(mut self, config: &ExportConfig) -> Self {
+        for skater in self.skaters.iter_mut() {
+            skater.pos = config.round3(skater.pos);
+            skater.stick_pos = config.round3(skater.stick_pos);
+            skater.body_turn = config.round(skater.body_turn);
+            skater.body_lean = config.round(skater.body_lean);
+        }
+        for puck in self.pucks.iter_mut() {
+            puck.pos = config.round3(puck.pos);
+        }
+        self
+    }
+}
+
+/// A frame as a single line of JSON, for newline-delimited output.
+pub fn frame_json(tick: usize, state: &HQMGameState) -> serde_json::Result<String> {
+    frame_json_with(tick, state, &ExportConfig::default())
+}
+
+pub fn frame_json_with(
+    tick: usize,
+    state: &HQMGameState,
+    config: &ExportConfig,
+) -> serde_json::Result<String> {
+    serde_json::to_string(&FrameRecord::new(tick, state).rounded(config))
+}
+
+/// A frame picked for a point on the game clock by `sample_positions`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PositionSample {
+    // The clock reading this sample is for. The frame's own time can differ slightly
+    // if no frame has exactly this reading.
+    pub clock: u32,
+    pub frame: FrameRecord,
+}
+
+/// Positions every `interval_ticks` hundredths of a second of game clock, starting from the
+/// clock reading at the start of each period. Each sample is the frame whose clock is
+/// nearest, so stoppages don't produce runs of identical samples like sampling by tick
+/// would. Nothing if `interval_ticks` is 0.
+pub fn sample_positions(frames: &[HQMGameState], interval_ticks: u32) -> Vec<PositionSample> {
+    let mut res = vec![];
+    if interval_ticks == 0 {
+        return res;
+    }
+    let mut start = 0;
+    while start < frames.len() {
+        let period = frames[start].period;
+        let end = frames[start..]
+            .iter()
+            .position(|x| x.period != period)
+            .map_or(frames.len(), |x| start + x);
+        // The clock counts down, so both the targets and the frames go from high to low
+        let distance = |tick: usize, clock: u32| frames[tick].time.abs_diff(clock);
+        // Recordings can stop before the period is over, only sample the clock they cover
+        let last_clock = frames[start..end].iter().map(|x| x.time).min().unwrap_or(0);
+        let mut tick = start;
+        let mut clock = Some(frames[start].time);
+        while let Some(target) = clock.filter(|x| *x >= last_clock) {
+            while tick + 1 < end && distance(tick + 1, target) < distance(tick, target) {
+                tick += 1;
+            }
+            res.push(PositionSample {
+                clock: target,
+                frame: FrameRecord::new(tick, &frames[tick]),
+            });
+            clock = target.checked_sub(interval_ticks);
+        }
+        start = end;
+    }
+    res
+}
+
+/// A skater in a `WebFrame`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebSkater {
+    pub x: f32,
+    pub z: f32,
+    pub rot: f32,
+    pub team: HQMTeam,
+    pub name: String,
+}
+
+/// A puck in a `WebFrame`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebPuck {
+    pub x: f32,
+    pub z: f32,
+}
+
+/// One frame for a browser replay viewer, seen from above. The field names and meanings
+/// are a stable contract, new fields may be added but these won't change:
+///
+/// - `time`: game clock in hundredths of a second, counting down, and `period`
+/// - `score`: `[red, blue]`
+/// - `x` and `z`: position as a fraction of the rink's width and length, 0 to 1 on the ice,
+///   with z = 0 at the blue end. Height is left out.
+/// - `rot`: the direction a skater faces in radians, 0 towards +z and π/2 towards +x
+/// - `team`: `"Red"` or `"Blue"`, `name`: the player's name
+#[derive(Debug, Clone, Serialize)]
+pub struct WebFrame {
+    pub time: u32,
+    pub period: u32,
+    pub score: [u32; 2],
+    pub skaters: Vec<WebSkater>,
+    pub pucks: Vec<WebPuck>,
+}
+
+/// Frames in the compact `WebFrame` format, much smaller than `frame_json`.
+pub fn to_web_frames(frames: &[HQMGameState], rink: &RinkGeometry) -> Vec<WebFrame> {
+    frames
+        .iter()
+        .map(|frame| WebFrame {
+            time: frame.time,
+            period: frame.period,
+            score: [frame.red_score, frame.blue_score],
+            skaters: frame
+                .skaters()
+                .map(|(player_index, team, skater)| {
+                    let facing = skater.facing();
+                    WebSkater {
+                        x: skater.pos.x / rink.width,
+                        z: skater.pos.z / rink.length,
+                        rot: facing.x.atan2(facing.z),
+                        team,
+                        name: player_name(frame, player_index).unwrap_or_default(),
+                    }
+                })
+                .collect(),
+            pucks: frame
+                .pucks()
+                .map(|(_, puck)| WebPuck {
+                    x: puck.pos.x / rink.width,
+                    z: puck.pos.z / rink.length,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::{HQMServerPlayer, HQMSkater};
+    use nalgebra::Point3;
+
+    // Two skaters moving along the rink for a few ticks
+    fn frames() -> Vec<HQMGameState> {
+        (0..5)
+            .map(|tick| {
+                let mut frame = HQMGameState::empty();
+                frame.period = 1;
+                frame.time = 30000 - tick;
+                for (player_index, team, name) in
+                    [(0, HQMTeam::Red, "alice"), (3, HQMTeam::Blue, "bob, jr")]
+                {
+                    let object = player_index + 1;
+                    frame.player_list[player_index] = Some(HQMServerPlayer {
+                        name: name.to_string(),
+                        team_and_skater: Some((object, team)),
+                    });
+                    let mut skater = HQMSkater::at(Point3::new(
+                        10.0 + player_index as f32,
+                        1.5,
+                        20.0 + tick as f32 * 0.25,
+                    ));
+                    skater.body_turn = 0.5;
+                    frame.objects[object] = HQMGameObject::Player(skater);
+                }
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn players_csv() {
+        let mut out = vec![];
+        write_players_csv(&frames(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[0], PLAYER_COLUMNS.join(","));
+        assert_eq!(lines[1], "0,1,30000,0,alice,Red,10,1.5,20,10,1.5,20,0.5,0");
+        assert_eq!(
+            lines[2],
+            "0,1,30000,3,\"bob, jr\",Blue,13,1.5,20,13,1.5,20,0.5,0"
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_round_trip() {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::{Float32Type, UInt32Type, UInt64Type};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let states = frames();
+        let mut data = vec![];
+        export_parquet(&states, &mut data).unwrap();
+
+        let path = std::env::temp_dir().join(format!("players-{}.parquet", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<_> = reader.map(|x| x.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        let rows = player_rows(&states);
+        assert_eq!(rows.len(), 10);
+        assert_eq!(
+            batches.iter().map(|x| x.num_rows()).sum::<usize>(),
+            rows.len()
+        );
+        let batch = &batches[0];
+        let names: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|x| x.name().clone())
+            .collect();
+        assert_eq!(names, PLAYER_COLUMNS);
+
+        let column = |name: &str| batch.column_by_name(name).unwrap();
+        let ticks = column("tick").as_primitive::<UInt64Type>();
+        let times = column("time").as_primitive::<UInt32Type>();
+        let players = column("player_index").as_primitive::<UInt32Type>();
+        let player_names = column("player_name").as_string::<i32>();
+        let teams = column("team").as_string::<i32>();
+        let zs = column("z").as_primitive::<Float32Type>();
+        let body_turns = column("body_turn").as_primitive::<Float32Type>();
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(ticks.value(i), row.tick as u64);
+            assert_eq!(times.value(i), states[row.tick].time);
+            assert_eq!(players.value(i), row.player_index as u32);
+            assert_eq!(player_names.value(i), row.player_name);
+            assert_eq!(teams.value(i), format!("{:?}", row.team));
+            assert_eq!(zs.value(i), 20.0 + row.tick as f32 * 0.25);
+            assert_eq!(body_turns.value(i), 0.5);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use nalgebra::{Matrix3, Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HQMServerPlayer {
+    pub name: String,
+    pub team_and_skater: Option<(usize, HQMTeam)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HQMGameObject {
+    None,
+    Player(HQMSkater),
+    Puck(HQMPuck),
+}
+
+/// A borrowed skater or puck, cheap to copy around unlike the objects themselves.
+#[derive(Debug, Copy, Clone)]
+pub enum ObjectRef<'a> {
+    Skater(&'a HQMSkater),
+    Puck(&'a HQMPuck),
+}
+
+impl<'a> ObjectRef<'a> {
+    pub fn pos(self) -> &'a Point3<f32> {
+        match self {
+            ObjectRef::Skater(skater) => &skater.pos,
+            ObjectRef::Puck(puck) => &puck.pos,
+        }
+    }
+
+    pub fn rot(self) -> &'a Matrix3<f32> {
+        match self {
+            ObjectRef::Skater(skater) => &skater.rot,
+            ObjectRef::Puck(puck) => &puck.rot,
+        }
+    }
+}
+
+impl HQMGameObject {
+    pub fn object_ref(&self) -> Option<ObjectRef<'_>> {
+        match self {
+            HQMGameObject::None => None,
+            HQMGameObject::Player(skater) => Some(ObjectRef::Skater(skater)),
+            HQMGameObject::Puck(puck) => Some(ObjectRef::Puck(puck)),
+        }
+    }
+
+    pub fn pos(&self) -> Option<&Point3<f32>> {
+        self.object_ref().map(ObjectRef::pos)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum HQMTeam {
+    Red,
+    Blue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HQMSkater {
+    pub pos: Point3<f32>,
+    pub rot: Matrix3<f32>,
+    pub stick_pos: Point3<f32>,  // Measured in meters
+    pub stick_rot: Matrix3<f32>, // Rotation matrix
+    // These are the only two animation values in the protocol, nothing else about the pose
+    // is sent. body_turn is what the server calls head_rot: how far the player is looking
+    // to the side, which turns the head and upper body. body_lean is the server's body_rot:
+    // the sideways lean of the body from the player's turn input. Both are in radians.
+    pub body_turn: f32,
+    pub body_lean: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HQMPuck {
+    pub pos: Point3<f32>,
+    pub rot: Matrix3<f32>,
+}
+
+impl HQMSkater {
+    /// A skater standing upright at `pos`, with the stick at the same position.
+    pub fn at(pos: Point3<f32>) -> Self {
+        HQMSkater {
+            pos,
+            rot: Matrix3::identity(),
+            stick_pos: pos,
+            stick_rot: Matrix3::identity(),
+            body_turn: 0.0,
+            body_lean: 0.0,
+        }
+    }
+
+    /// Unit vector in the direction the skater is facing. The rotation matrix columns are the
+    /// object's right (x), up (y) and backward (z) directions, so forward is the negated third
+    /// column. A red skater with the identity rotation faces towards low z, like at a faceoff.
+    pub fn facing(&self) -> Vector3<f32> {
+        -self.rot.column(2)
+    }
+}
+
+impl Default for HQMSkater {
+    fn default() -> Self {
+        Self::at(Point3::origin())
+    }
+}
+
+impl HQMPuck {
+    pub fn at(pos: Point3<f32>) -> Self {
+        HQMPuck {
+            pos,
+            rot: Matrix3::identity(),
+        }
+    }
+
+    /// The negated third column of the rotation, like `HQMSkater::facing`.
+    /// A puck is round, so this only tells how far it has spun.
+    pub fn facing(&self) -> Vector3<f32> {
+        -self.rot.column(2)
+    }
+}
+
+impl Default for HQMPuck {
+    fn default() -> Self {
+        Self::at(Point3::origin())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HQMMessage {
+    PlayerUpdate {
+        player_name: String,
+        object: Option<(usize, HQMTeam)>,
+        player_index: usize,
+        in_server: bool,
+    },
+    Goal {
+        team: HQMTeam,
+        goal_player_index: Option<usize>,
+        assist_player_index: Option<usize>,
+    },
+    Chat {
+        player_index: Option<usize>,
+        message: String,
+    },
+    // Only produced by the lenient parser. raw_bits holds the bits following the type,
+    // up to where the next packet seems to start.
+    Unknown {
+        message_type: u32,
+        raw_bits: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HQMGameState {
+    pub packet_number: u32,
+    pub red_score: u32,
+    pub blue_score: u32,
+    pub period: u32,
+    pub game_over: bool,
+    pub time: u32,
+    pub goal_message_timer: u32,
+    pub objects: Vec<HQMGameObject>,
+    pub player_list: Vec<Option<HQMServerPlayer>>,
+    pub messages_in_this_packet: Vec<HQMMessage>,
+    // Position of the first message in the packet's message window and the window size.
+    // Messages before the position reached by earlier packets are repeats and were dropped.
+    pub msg_pos: u32,
+    pub message_num: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scoreboard {
+    pub period: u32,
+    pub clock: String,
+    pub red: u32,
+    pub blue: u32,
+    pub celebrating: bool,
+}
+
+/// Formats a game clock value (hundredths of a second) as M:SS.
+pub fn format_clock(time: u32) -> String {
+    let seconds = time / 100;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// Server ticks per second, replays record one packet per tick
+pub const DEFAULT_TICK_RATE: f32 = 100.0;
+
+/// Real time elapsed since the start of the replay at the given frame.
+/// Frames map 1:1 to recorded packets, so if the recorder dropped packets
+/// everything after the gap will be early by the missing ticks.
+pub fn frame_timestamp(frame_index: usize, tick_rate: f32) -> Duration {
+    Duration::from_secs_f64(frame_index as f64 / tick_rate as f64)
+}
+
+/// Every frame together with the one before it, starting with the first two.
+pub fn frame_pairs(
+    frames: &[HQMGameState],
+) -> impl Iterator<Item = (&HQMGameState, &HQMGameState)> {
+    frames.windows(2).map(|pair| (&pair[0], &pair[1]))
+}
+
+/// Overlapping runs of `n` consecutive frames. Nothing if there are fewer than `n` frames.
+/// Panics if `n` is 0.
+pub fn frame_windows(frames: &[HQMGameState], n: usize) -> impl Iterator<Item = &[HQMGameState]> {
+    frames.windows(n)
+}
+
+/// Anchors a replay to the real time its first frame was recorded.
+#[cfg(feature = "chrono")]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReplayStartTime {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub first_packet_number: u32,
+}
+
+/// Ties the first frame to `start`, e.g. the moment a stream of the game shows it.
+#[cfg(feature = "chrono")]
+pub fn set_start_time(
+    frames: &[HQMGameState],
+    start: chrono::DateTime<chrono::Utc>,
+) -> ReplayStartTime {
+    ReplayStartTime {
+        start,
+        first_packet_number: frames.first().map_or(0, |x| x.packet_number),
+    }
+}
+
+impl Default for HQMGameState {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl HQMGameState {
+    /// A warmup state with no objects, no players and no messages,
+    /// sized like the states the parser produces.
+    pub fn empty() -> Self {
+        HQMGameState {
+            packet_number: 0,
+            red_score: 0,
+            blue_score: 0,
+            period: 0,
+            game_over: false,
+            time: 0,
+            goal_message_timer: 0,
+            objects: vec![HQMGameObject::None; 32],
+            player_list: vec![None; 63],
+            messages_in_this_packet: vec![],
+            msg_pos: 0,
+            message_num: 0,
+        }
+    }
+
+    /// When this frame happened in real time. Packet numbers count server ticks, so frames
+    /// dropped by the recorder don't shift the frames after them.
+    #[cfg(feature = "chrono")]
+    pub fn wall_time(
+        &self,
+        start: &ReplayStartTime,
+        tick_rate: f32,
+    ) -> chrono::DateTime<chrono::Utc> {
+        let ticks = self.packet_number.wrapping_sub(start.first_packet_number);
+        let elapsed = Duration::from_secs_f64(ticks as f64 / tick_rate as f64);
+        start.start + chrono::TimeDelta::from_std(elapsed).unwrap_or(chrono::TimeDelta::MAX)
+    }
+
+    pub fn scoreboard(&self) -> Scoreboard {
+        Scoreboard {
+            period: self.period,
+            clock: format_clock(self.time),
+            red: self.red_score,
+            blue: self.blue_score,
+            celebrating: self.goal_message_timer > 0,
+        }
+    }
+
+    /// Skaters currently on the ice, as (player index, team, skater).
+    pub fn skaters(&self) -> impl Iterator<Item = (usize, HQMTeam, &HQMSkater)> {
+        self.player_list
+            .iter()
+            .enumerate()
+            .filter_map(move |(player_index, player)| {
+                let (object_index, team) = player.as_ref()?.team_and_skater?;
+                match self.objects.get(object_index) {
+                    Some(HQMGameObject::Player(skater)) => Some((player_index, team, skater)),
+                    _ => None,
+                }
+            })
+    }
+
+    /// Objects within `radius` meters of `p`, as (slot, object, distance), nearest first.
+    pub fn objects_near(&self, p: &Point3<f32>, radius: f32) -> Vec<(usize, &HQMGameObject, f32)> {
+        let mut res: Vec<_> = self
+            .objects
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, object)| {
+                let dist = (object.pos()? - p).norm();
+                (dist <= radius).then_some((slot, object, dist))
+            })
+            .collect();
+        res.sort_by(|a, b| a.2.total_cmp(&b.2));
+        res
+    }
+
+    /// Every skater and puck as (object slot, object), without copying anything.
+    pub fn object_refs(&self) -> impl Iterator<Item = (usize, ObjectRef<'_>)> {
+        self.objects
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, object)| Some((slot, object.object_ref()?)))
+    }
+
+    /// All pucks on the ice, as (object slot, puck). Some game modes use more than one.
+    pub fn pucks(&self) -> impl Iterator<Item = (usize, &HQMPuck)> {
+        self.objects
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, x)| match x {
+                HQMGameObject::Puck(puck) => Some((slot, puck)),
+                _ => None,
+            })
+    }
+
+    /// The puck in the lowest object slot. Normal games only have one,
+    /// use `pucks` when several may be present.
+    pub fn puck(&self) -> Option<&HQMPuck> {
+        self.pucks().next().map(|(_, puck)| puck)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::UnitQuaternion;
+
+    #[test]
+    fn identity_rotation_faces_low_z() {
+        let skater = HQMSkater::default();
+        assert_eq!(skater.facing(), Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(HQMPuck::default().facing(), Vector3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn skater_turned_to_the_side_faces_high_x() {
+        // A quarter turn around the vertical axis, away from facing low z
+        let turn =
+            UnitQuaternion::from_axis_angle(&Vector3::y_axis(), -std::f32::consts::FRAC_PI_2);
+        let skater = HQMSkater {
+            rot: *turn.to_rotation_matrix().matrix(),
+            ..HQMSkater::default()
+        };
+        let facing = skater.facing();
+        assert!((facing - Vector3::new(1.0, 0.0, 0.0)).norm() < 1e-6);
+    }
+}
@@ -0,0 +1,243 @@
+//! Sidecar index for seeking in large replays without decoding them from the start.
+//!
+//! Object positions are delta coded against an earlier packet, so a packet can't be decoded
+//! on its own. Every `KEYFRAME_INTERVAL` packets the index stores a keyframe with the parser
+//! state at that point: the roster, the message window and the decoded objects of the earlier
+//! packets that the packets after the keyframe are delta coded against. That is usually just
+//! the packet right before the keyframe. `open_at` restores the nearest keyframe at or before
+//! the requested frame and decodes forward from there with the options the index was built
+//! with, which gives exactly the same states as a full parse.
+//!
+//! Entries are serialized as plain arrays to keep the file small.
+
+use crate::hqm_game::HQMServerPlayer;
+use crate::hqm_options::ParserOptions;
+use crate::hqm_parse::{HQMObjectPacket, HQMParseError};
+use crate::hqm_replay::{delta_reference, ParserState, ReplayParser, ReplayStates};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// Packets between keyframes
+const KEYFRAME_INTERVAL: usize = 1000;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "EntryRow", into = "EntryRow")]
+pub struct IndexEntry {
+    // Byte offset of the packet in the replay file
+    pub offset: usize,
+    pub packet_number: u32,
+    pub period: u32,
+    pub time: u32,
+    pub red_score: u32,
+    pub blue_score: u32,
+}
+
+// IndexEntry in the order of its fields
+type EntryRow = (usize, u32, u32, u32, u32, u32);
+
+impl From<EntryRow> for IndexEntry {
+    fn from(row: EntryRow) -> Self {
+        IndexEntry {
+            offset: row.0,
+            packet_number: row.1,
+            period: row.2,
+            time: row.3,
+            red_score: row.4,
+            blue_score: row.5,
+        }
+    }
+}
+
+impl From<IndexEntry> for EntryRow {
+    fn from(x: IndexEntry) -> Self {
+        (
+            x.offset,
+            x.packet_number,
+            x.period,
+            x.time,
+            x.red_score,
+            x.blue_score,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub frame: usize,
+    pub offset: usize,
+    pub(crate) packets: Vec<(u32, Vec<HQMObjectPacket>)>,
+    pub(crate) player_list: Vec<Option<HQMServerPlayer>>,
+    pub(crate) current_msg_pos: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayIndexFile {
+    // What the index was built with, seeking decodes with the same options
+    pub options: ParserOptions,
+    // One entry per packet
+    pub entries: Vec<IndexEntry>,
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl ReplayIndexFile {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(s: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Decodes the whole replay once and records where each packet starts.
+pub fn build_index(data: &[u8]) -> Result<ReplayIndexFile, HQMParseError> {
+    build_index_with(data, ParserOptions::default())
+}
+
+/// Like `build_index`, decoding with the given options. They are stored in the index.
+pub fn build_index_with(
+    data: &[u8],
+    options: ParserOptions,
+) -> Result<ReplayIndexFile, HQMParseError> {
+    let mut states = ReplayParser::with_options(data, options.clone()).states()?;
+    let mut entries: Vec<IndexEntry> = vec![];
+    let mut keyframes = vec![];
+    // The packet numbers each keyframe needs, filled in as later packets refer back to them
+    let mut needed: Vec<Vec<u32>> = vec![];
+    let mut decoded_since_keyframe = HashSet::new();
+    loop {
+        let frame = entries.len();
+        let offset = states.position();
+        if frame.is_multiple_of(KEYFRAME_INTERVAL) {
+            keyframes.push(states.parser_state().keyframe(frame, offset));
+            needed.push(vec![]);
+            decoded_since_keyframe.clear();
+        }
+        let state = match states.next() {
+            Some(state) => state?,
+            None => break,
+        };
+        // A reference to a packet from before the last keyframe has to be stored with it
+        let reference = delta_reference(data, offset);
+        if let Some(needed) = needed.last_mut() {
+            if !decoded_since_keyframe.contains(&reference) && !needed.contains(&reference) {
+                needed.push(reference);
+            }
+        }
+        decoded_since_keyframe.insert(state.packet_number);
+        entries.push(IndexEntry {
+            offset,
+            packet_number: state.packet_number,
+            period: state.period,
+            time: state.time,
+            red_score: state.red_score,
+            blue_score: state.blue_score,
+        });
+    }
+    // Every packet decoded is still in the parser state, take the ones the keyframes need
+    for (keyframe, needed) in keyframes.iter_mut().zip(needed) {
+        keyframe.packets = states.parser_state().saved_packets(&needed);
+    }
+    Ok(ReplayIndexFile {
+        options,
+        entries,
+        keyframes,
+    })
+}
+
+/// States starting at `frame`, using the index to skip most of the replay before it.
+/// Frames past the end of the replay give an empty iterator.
+pub fn open_at<'a>(
+    data: &'a [u8],
+    index: &ReplayIndexFile,
+    frame: usize,
+) -> Result<ReplayStates<'a>, HQMParseError> {
+    let options = index.options.clone();
+    let (start, mut states) = match index.keyframes.iter().rev().find(|x| x.frame <= frame) {
+        Some(keyframe) => {
+            let parser_state = ParserState::from_keyframe(options, keyframe);
+            let states = ReplayStates::resume(data, keyframe.offset, parser_state);
+            (keyframe.frame, states)
+        }
+        None => (0, ReplayParser::with_options(data, options).states()?),
+    };
+    for _ in start..frame {
+        match states.next() {
+            Some(state) => {
+                state?;
+            }
+            None => break,
+        }
+    }
+    Ok(states)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::HQMGameState;
+    use crate::test_util::*;
+
+    // A puck moving a little every tick, delta coded against the packet before. The packets
+    // right after the second keyframe refer back past it.
+    fn replay() -> Vec<u8> {
+        let mut packets = packets(KEYFRAME_INTERVAL as u32 + 50);
+        for (i, packet) in packets.iter_mut().enumerate() {
+            packet.objects = vec![puck_packet(10.0 + i as f32 * 0.01, 0.5, 20.0)];
+            packet.reference = i.checked_sub(1).map(|x| x as u32);
+        }
+        let k = KEYFRAME_INTERVAL;
+        packets[k].reference = Some(k as u32 - 2);
+        packets[k + 1].reference = Some(k as u32 - 2);
+        replay_bytes(&packets)
+    }
+
+    fn collect(states: ReplayStates) -> Vec<HQMGameState> {
+        states.map(|x| x.unwrap()).collect()
+    }
+
+    #[test]
+    fn keyframes_keep_only_referenced_packets() {
+        let data = replay();
+        let index = build_index(&data).unwrap();
+        assert_eq!(index.entries.len(), KEYFRAME_INTERVAL + 50);
+        assert_eq!(index.keyframes.len(), 2);
+        assert!(index.keyframes[0].packets.is_empty());
+        let kept: Vec<_> = index.keyframes[1].packets.iter().map(|x| x.0).collect();
+        assert_eq!(kept, vec![KEYFRAME_INTERVAL as u32 - 2]);
+    }
+
+    #[test]
+    fn open_at_matches_full_parse() {
+        let data = replay();
+        let index =
+            ReplayIndexFile::from_json(&build_index(&data).unwrap().to_json().unwrap()).unwrap();
+        let full = parse_all_states(&data, ParserOptions::default());
+        let k = KEYFRAME_INTERVAL;
+        for frame in [0, 1, k - 1, k, k + 1, k + 2, k + 49] {
+            assert_eq!(
+                collect(open_at(&data, &index, frame).unwrap()),
+                full[frame..]
+            );
+        }
+        assert!(collect(open_at(&data, &index, k + 50).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn open_at_uses_the_index_options() {
+        let data = replay();
+        let options = ParserOptions::default().position_scale(512.0);
+        let index = build_index_with(&data, options.clone()).unwrap();
+        let full = parse_all_states(&data, options);
+        let frame = KEYFRAME_INTERVAL + 1;
+        let states = collect(open_at(&data, &index, frame).unwrap());
+        assert_eq!(states, full[frame..]);
+        assert_eq!(states[0].puck().unwrap().pos.z, 40.0);
+    }
+
+    fn parse_all_states(data: &[u8], options: ParserOptions) -> Vec<HQMGameState> {
+        ReplayParser::with_options(data, options)
+            .parse()
+            .unwrap()
+            .states
+    }
+}
@@ -0,0 +1,115 @@
+use crate::hqm_parse::PositionClampPolicy;
+use crate::hqm_replay::HEADER_SIZE;
+use serde::{Deserialize, Serialize};
+
+/// Knobs for the replay parser. The defaults reproduce the plain `parse_replay` behavior.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParserOptions {
+    // Fail on packets that don't start with the expected marker or are cut off
+    pub(crate) strict: bool,
+    // Drop a cut off packet at the end of the data instead of decoding zero-filled bits
+    pub(crate) lenient: bool,
+    // Replace invalid UTF-8 in names and chat instead of failing
+    pub(crate) lossy_strings: bool,
+    // Keep the undecoded object packets next to the states
+    pub(crate) keep_raw_packets: bool,
+    pub(crate) max_objects: usize,
+    // Network position units per meter
+    pub(crate) position_scale: f32,
+    // Length of player names in 7-bit characters
+    pub(crate) string_length: usize,
+    // Ignore a goal message that repeats the previous one without the score changing
+    pub(crate) collapse_goal_repeats: bool,
+    // Where the first packet starts. The standard header is the 8 bytes right before it,
+    // if there is room for one.
+    pub(crate) header_skip: usize,
+    // Look for the first packet instead of trusting header_skip
+    pub(crate) detect_header: bool,
+    // Turn object packets into floats, only off for raw frames
+    pub(crate) convert_objects: bool,
+    // What to do when a delta coded position goes below zero
+    pub(crate) position_clamp: PositionClampPolicy,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions {
+            strict: false,
+            lenient: false,
+            lossy_strings: false,
+            keep_raw_packets: false,
+            max_objects: 32,
+            position_scale: 1024.0,
+            string_length: 31,
+            collapse_goal_repeats: false,
+            header_skip: HEADER_SIZE,
+            detect_header: false,
+            convert_objects: true,
+            position_clamp: PositionClampPolicy::ClampToZero,
+        }
+    }
+}
+
+impl ParserOptions {
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    pub fn lossy_strings(mut self, lossy_strings: bool) -> Self {
+        self.lossy_strings = lossy_strings;
+        self
+    }
+
+    pub fn keep_raw_packets(mut self, keep_raw_packets: bool) -> Self {
+        self.keep_raw_packets = keep_raw_packets;
+        self
+    }
+
+    pub fn max_objects(mut self, max_objects: usize) -> Self {
+        self.max_objects = max_objects;
+        self
+    }
+
+    pub fn position_scale(mut self, position_scale: f32) -> Self {
+        self.position_scale = position_scale;
+        self
+    }
+
+    pub fn string_length(mut self, string_length: usize) -> Self {
+        self.string_length = string_length;
+        self
+    }
+
+    pub fn collapse_goal_repeats(mut self, collapse_goal_repeats: bool) -> Self {
+        self.collapse_goal_repeats = collapse_goal_repeats;
+        self
+    }
+
+    pub fn header_skip(mut self, header_skip: usize) -> Self {
+        self.header_skip = header_skip;
+        self
+    }
+
+    /// Scans the start of the data for the first packet, for recorders that write extra bytes
+    /// before it. An offset is accepted if a marker byte is followed by a packet that can be
+    /// read without errors and another marker byte, or the end of the data, comes right after.
+    /// Only the first kilobyte is searched. `StreamingParser` keeps searching on each `feed`
+    /// until the first packet has been written.
+    pub fn detect_header(mut self, detect_header: bool) -> Self {
+        self.detect_header = detect_header;
+        self
+    }
+
+    /// How delta coded positions that would go below zero are handled. Clamping to zero is
+    /// the default. `AllowNegative` keeps them, and positions in meters come out negative.
+    pub fn position_clamp(mut self, position_clamp: PositionClampPolicy) -> Self {
+        self.position_clamp = position_clamp;
+        self
+    }
+}
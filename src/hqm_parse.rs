@@ -1,5 +1,9 @@
 use nalgebra::{Matrix3, Vector3};
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
+use std::error::Error;
+use std::fmt;
+use std::io;
 
 const UXP: Vector3<f32> = Vector3::new(1.0, 0.0, 0.0);
 const UXN: Vector3<f32> = Vector3::new(-1.0, 0.0, 0.0);
@@ -8,7 +12,7 @@ const UYN: Vector3<f32> = Vector3::new(0.0, -1.0, 0.0);
 const UZP: Vector3<f32> = Vector3::new(0.0, 0.0, 1.0);
 const UZN: Vector3<f32> = Vector3::new(0.0, 0.0, -1.0);
 
-const TABLE: [[&'static Vector3<f32>; 3]; 8] = [
+const TABLE: [[&Vector3<f32>; 3]; 8] = [
     [&UYP, &UXP, &UZP],
     [&UYP, &UZP, &UXN],
     [&UYP, &UZN, &UXP],
@@ -31,9 +35,9 @@ pub fn convert_matrix_from_network(b: u8, v1: u32, v2: u32) -> Matrix3<f32> {
 fn convert_rot_column_from_network(b: u8, v: u32) -> Vector3<f32> {
     let start = v & 7;
 
-    let mut temp1 = TABLE[start as usize][0].clone();
-    let mut temp2 = TABLE[start as usize][1].clone();
-    let mut temp3 = TABLE[start as usize][2].clone();
+    let mut temp1 = *TABLE[start as usize][0];
+    let mut temp2 = *TABLE[start as usize][1];
+    let mut temp3 = *TABLE[start as usize][2];
     let mut pos = 3;
     while pos < b {
         let step = (v >> pos) & 3;
@@ -66,10 +70,212 @@ fn convert_rot_column_from_network(b: u8, v: u32) -> Vector3<f32> {
     (temp1 + temp2 + temp3).normalize()
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HQMParseError {
+    UnexpectedEof,
+    InvalidPacketMarker {
+        pos: usize,
+        marker: u8,
+    },
+    InvalidString {
+        pos: usize,
+    },
+    Truncated {
+        declared: usize,
+        available: usize,
+    },
+    UnknownMessageType {
+        pos: usize,
+        message_type: u32,
+    },
+    NoPacketFound,
+    // Shorter than the header, e.g. an empty file
+    TooSmall {
+        size: usize,
+    },
+    // pos and bit are where the offending bits start
+    UnknownObjectType {
+        pos: usize,
+        bit: u8,
+        object_type: u32,
+    },
+    // A delta coded value without an earlier packet to apply it to
+    MissingDeltaReference {
+        pos: usize,
+        bit: u8,
+    },
+    // A delta took a position below zero with PositionClampPolicy::Error
+    NegativePosition {
+        pos: usize,
+        bit: u8,
+        value: i32,
+    },
+    // Reading the replay file failed
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+    // Parsing one of the files of a split recording failed, part 1 is the first file
+    SplitPart {
+        part: usize,
+        error: Box<HQMParseError>,
+    },
+}
+
+impl fmt::Display for HQMParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HQMParseError::UnexpectedEof => write!(f, "unexpected end of replay data"),
+            HQMParseError::InvalidPacketMarker { pos, marker } => {
+                write!(f, "invalid packet marker {} at byte {}", marker, pos)
+            }
+            HQMParseError::InvalidString { pos } => {
+                write!(f, "invalid UTF-8 string at byte {}", pos)
+            }
+            HQMParseError::Truncated {
+                declared,
+                available,
+            } => write!(
+                f,
+                "replay declares {} bytes of data but only {} are present",
+                declared, available
+            ),
+            HQMParseError::UnknownMessageType { pos, message_type } => {
+                write!(f, "unknown message type {} at byte {}", message_type, pos)
+            }
+            HQMParseError::NoPacketFound => write!(f, "could not find the first packet"),
+            HQMParseError::TooSmall { size } => write!(
+                f,
+                "replay is only {} bytes, too small to hold a replay header",
+                size
+            ),
+            HQMParseError::UnknownObjectType {
+                pos,
+                bit,
+                object_type,
+            } => write!(
+                f,
+                "unknown object type {} at byte {} bit {}",
+                object_type, pos, bit
+            ),
+            HQMParseError::MissingDeltaReference { pos, bit } => write!(
+                f,
+                "delta coded value without a previous value at byte {} bit {}",
+                pos, bit
+            ),
+            HQMParseError::NegativePosition { pos, bit, value } => write!(
+                f,
+                "delta coded value went negative ({}) at byte {} bit {}",
+                value, pos, bit
+            ),
+            HQMParseError::Io { message, .. } => {
+                write!(f, "could not read replay file: {}", message)
+            }
+            HQMParseError::SplitPart { part, error } => {
+                write!(f, "part {} of the split replay: {}", part, error)
+            }
+        }
+    }
+}
+
+impl Error for HQMParseError {}
+
+impl From<io::Error> for HQMParseError {
+    fn from(e: io::Error) -> Self {
+        HQMParseError::Io {
+            kind: e.kind(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Something odd about the replay that didn't stop it from being parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWarning {
+    // The header's data length doesn't match the data present. Some recorders write 0 or
+    // a stale value, so the length is only advisory and packets are read until the end.
+    LengthMismatch {
+        declared: usize,
+        available: usize,
+    },
+    // A score went down from one tick to the next, which points at a desync or packets out of
+    // order. Also happens if the server starts a new game in the middle of the recording.
+    ScoreRegression {
+        tick: usize,
+        from: (u32, u32),
+        to: (u32, u32),
+    },
+    // Bytes after the last packet that don't look like another one, e.g. a footer.
+    // They are ignored.
+    TrailingData {
+        offset: usize,
+        length: usize,
+    },
+    // Where two recordings were joined, the second one doesn't continue where the first one
+    // ended: the period or a score went backwards. Both are (period, red score, blue score).
+    SplitMismatch {
+        tick: usize,
+        from: (u32, u32, u32),
+        to: (u32, u32, u32),
+    },
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseWarning::LengthMismatch {
+                declared,
+                available,
+            } => write!(
+                f,
+                "header declares {} bytes of data but {} are present",
+                declared, available
+            ),
+            ParseWarning::ScoreRegression { tick, from, to } => write!(
+                f,
+                "score went from {}-{} to {}-{} at tick {}",
+                from.0, from.1, to.0, to.1, tick
+            ),
+            ParseWarning::TrailingData { offset, length } => write!(
+                f,
+                "ignored {} bytes after the last packet at byte {}",
+                length, offset
+            ),
+            ParseWarning::SplitMismatch { tick, from, to } => write!(
+                f,
+                "recordings joined at tick {} don't line up: period {} at {}-{}, then period {} at {}-{}",
+                tick, from.0, from.1, from.2, to.0, to.1, to.2
+            ),
+        }
+    }
+}
+
+// Byte order of the aligned multi-byte reads. HQM itself always writes little-endian.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ByteOrder {
+    #[default]
+    LittleEndian,
+    BigEndian,
+}
+
+// What read_pos does when a delta takes a value below zero. That shouldn't happen with a
+// well-formed replay, but corrupted or misaligned data can produce it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PositionClampPolicy {
+    // Use 0 instead
+    #[default]
+    ClampToZero,
+    // Keep the value, stored as its two's complement bits
+    AllowNegative,
+    // Fail with HQMParseError::NegativePosition
+    Error,
+}
+
 pub struct HQMMessageReader<'a> {
     buf: &'a [u8],
     pub(crate) pos: usize,
     pub(crate) bit_pos: u8,
+    byte_order: ByteOrder,
 }
 
 impl<'a> HQMMessageReader<'a> {
@@ -84,8 +290,8 @@ impl<'a> HQMMessageReader<'a> {
     pub fn read_byte_aligned(&mut self) -> u8 {
         self.align();
         let res = self.safe_get_byte(self.pos);
-        self.pos = self.pos + 1;
-        return res;
+        self.pos += 1;
+        res
     }
 
     pub fn read_u32_aligned(&mut self) -> u32 {
@@ -94,31 +300,63 @@ impl<'a> HQMMessageReader<'a> {
         let b2: u32 = self.safe_get_byte(self.pos + 1).into();
         let b3: u32 = self.safe_get_byte(self.pos + 2).into();
         let b4: u32 = self.safe_get_byte(self.pos + 3).into();
-        self.pos = self.pos + 4;
-        return b1 | b2 << 8 | b3 << 16 | b4 << 24;
+        self.pos += 4;
+        match self.byte_order {
+            ByteOrder::LittleEndian => b1 | b2 << 8 | b3 << 16 | b4 << 24,
+            ByteOrder::BigEndian => b1 << 24 | b2 << 16 | b3 << 8 | b4,
+        }
+    }
+
+    pub fn read_f32_aligned(&mut self) -> f32 {
+        f32::from_bits(self.read_u32_aligned())
     }
 
-    pub fn read_pos(&mut self, b: u8, old_value: Option<u32>) -> u32 {
+    pub fn read_pos(
+        &mut self,
+        b: u8,
+        old_value: Option<u32>,
+        clamp: PositionClampPolicy,
+    ) -> Result<u32, HQMParseError> {
+        let (pos, bit) = (self.pos, self.bit_pos);
         let pos_type = self.read_bits(2);
-        match pos_type {
-            0 => {
-                let diff = self.read_bits_signed(3);
-                let old_value = old_value.unwrap() as i32;
-                (old_value + diff).max(0) as u32
-            }
-            1 => {
-                let diff = self.read_bits_signed(6);
-                let old_value = old_value.unwrap() as i32;
-                (old_value + diff).max(0) as u32
-            }
-            2 => {
-                let diff = self.read_bits_signed(12);
-                let old_value = old_value.unwrap() as i32;
-                (old_value + diff).max(0) as u32
-            }
-            3 => self.read_bits(b),
-            _ => panic!(),
+        if self.is_past_end() {
+            // Zero-filled bits from a truncated packet, there may be no old value to apply them to
+            return Ok(0);
         }
+        let diff_bits = match pos_type {
+            0 => 3,
+            1 => 6,
+            2 => 12,
+            // 3, the value itself rather than a difference
+            _ => return Ok(self.read_bits(b)),
+        };
+        let diff = self.read_bits_signed(diff_bits);
+        let old_value = old_value.ok_or(HQMParseError::MissingDeltaReference { pos, bit })? as i32;
+        let value = old_value.wrapping_add(diff);
+        match clamp {
+            _ if value >= 0 => Ok(value as u32),
+            PositionClampPolicy::ClampToZero => Ok(0),
+            PositionClampPolicy::AllowNegative => Ok(value as u32),
+            PositionClampPolicy::Error => Err(HQMParseError::NegativePosition { pos, bit, value }),
+        }
+    }
+
+    // Skips a value written by the same encoding as read_pos without decoding it
+    pub fn skip_pos(&mut self, b: u8) {
+        let pos_type = self.read_bits(2);
+        let bits = match pos_type {
+            0 => 3,
+            1 => 6,
+            2 => 12,
+            _ => b,
+        };
+        self.skip_bits(bits as usize);
+    }
+
+    pub fn skip_bits(&mut self, b: usize) {
+        let total = self.bit_pos as usize + b;
+        self.pos += total / 8;
+        self.bit_pos = (total % 8) as u8;
     }
 
     pub fn read_bits_signed(&mut self, b: u8) -> i32 {
@@ -146,7 +384,7 @@ impl<'a> HQMMessageReader<'a> {
             };
             let a = (self.safe_get_byte(self.pos) >> self.bit_pos) & mask;
             let a: u32 = a.into();
-            res = res | (a << p);
+            res |= a << p;
 
             if bits_remaining >= bits_possible_to_write {
                 bits_remaining -= bits_possible_to_write;
@@ -158,7 +396,39 @@ impl<'a> HQMMessageReader<'a> {
                 bits_remaining = 0;
             }
         }
-        return res;
+        res
+    }
+
+    // Reads the bits up to the next byte equal to `marker`, or to the end of the buffer,
+    // packed into bytes starting at the current bit
+    pub fn read_until_byte(&mut self, marker: u8) -> Vec<u8> {
+        let search_from = if self.bit_pos > 0 {
+            self.pos + 1
+        } else {
+            self.pos
+        };
+        let end = self
+            .buf
+            .iter()
+            .skip(search_from)
+            .position(|&x| x == marker)
+            .map_or(self.buf.len(), |i| search_from + i);
+        let mut res = vec![];
+        while self.pos < end {
+            let remaining = (end - self.pos) * 8 - self.bit_pos as usize;
+            res.push(self.read_bits(remaining.min(8) as u8) as u8);
+        }
+        res
+    }
+
+    // Unread bits left in the buffer
+    pub fn bits_remaining(&self) -> usize {
+        (self.buf.len() * 8).saturating_sub(self.pos * 8 + self.bit_pos as usize)
+    }
+
+    // True if bits beyond the end of the buffer have been read
+    pub fn is_past_end(&self) -> bool {
+        self.pos > self.buf.len() || (self.pos == self.buf.len() && self.bit_pos > 0)
     }
 
     pub fn align(&mut self) {
@@ -173,34 +443,94 @@ impl<'a> HQMMessageReader<'a> {
         self.bit_pos = 0;
     }
 
+    // Moves to the start of the next packet in a replay. The recorder saves each packet up to
+    // and including the byte that holds the next unwritten bit, so every packet ends with the
+    // partially used byte the last message ended in, or with one whole unused byte if the
+    // messages happened to end on a byte boundary. Either way, the next packet starts at the
+    // byte after the current one, even when bit_pos is 0. The unused bits are not checked,
+    // nothing guarantees what they contain.
+    pub fn consume_tick_terminator(&mut self) {
+        self.next();
+    }
+
     pub fn new(buf: &'a [u8]) -> Self {
+        Self::with_byte_order(buf, ByteOrder::LittleEndian)
+    }
+
+    pub fn with_byte_order(buf: &'a [u8], byte_order: ByteOrder) -> Self {
         HQMMessageReader {
             buf,
             pos: 0,
             bit_pos: 0,
+            byte_order,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum HQMObjectPacket {
     None,
     Puck(HQMPuckPacket),
     Skater(HQMSkaterPacket),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HQMSkaterPacket {
     pub pos: (u32, u32, u32),
     pub rot: (u32, u32),
     pub stick_pos: (u32, u32, u32),
     pub stick_rot: (u32, u32),
+    // The last two 16-bit values of a skater. The server writes its head_rot and body_rot
+    // here as (angle + 2) * 8192, so both cover -2 to 2 radians in steps of 1/8192.
     pub body_turn: u32,
     pub body_lean: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HQMPuckPacket {
     pub pos: (u32, u32, u32),
     pub rot: (u32, u32),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_reads_follow_byte_order() {
+        let bytes = [0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x80, 0x3F];
+        let mut le = HQMMessageReader::with_byte_order(&bytes, ByteOrder::LittleEndian);
+        let mut be = HQMMessageReader::with_byte_order(&bytes, ByteOrder::BigEndian);
+        assert_eq!(le.read_u32_aligned(), 0x04030201);
+        assert_eq!(be.read_u32_aligned(), 0x01020304);
+        assert_eq!(le.read_f32_aligned(), 1.0);
+        assert_eq!(be.read_f32_aligned(), f32::from_bits(0x0000803F));
+    }
+
+    #[test]
+    fn default_byte_order_is_little_endian() {
+        let bytes = [0x78, 0x56, 0x34, 0x12];
+        assert_eq!(HQMMessageReader::new(&bytes).read_u32_aligned(), 0x12345678);
+    }
+
+    #[test]
+    fn aligned_reads_skip_to_the_next_byte() {
+        let bytes = [0xFF, 0x01, 0x00, 0x00, 0x00];
+        let mut reader = HQMMessageReader::with_byte_order(&bytes, ByteOrder::BigEndian);
+        reader.read_bits(3);
+        assert_eq!(reader.read_u32_aligned(), 0x01000000);
+        assert_eq!(reader.pos, 5);
+    }
+
+    #[test]
+    fn tick_terminator_always_moves_to_the_next_byte() {
+        let bytes = [0xFF; 4];
+        let mut reader = HQMMessageReader::new(&bytes);
+        reader.read_bits(3);
+        reader.consume_tick_terminator();
+        assert_eq!((reader.pos, reader.bit_pos), (1, 0));
+        // On a byte boundary a whole byte is skipped
+        reader.consume_tick_terminator();
+        assert_eq!((reader.pos, reader.bit_pos), (2, 0));
+    }
+}
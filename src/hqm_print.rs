@@ -0,0 +1,114 @@
+use crate::hqm_game::{HQMGameObject, HQMGameState, HQMMessage};
+use std::io::{self, Write};
+
+/// Goals and chat in the packet, one per line.
+pub fn print_messages<W: Write>(state: &HQMGameState, w: &mut W) -> io::Result<()> {
+    let player_name = |i: usize| {
+        let p = state.player_list[i].as_ref();
+        p.map(|p| p.name.clone())
+    };
+    for msg in state.messages_in_this_packet.iter() {
+        match msg {
+            HQMMessage::PlayerUpdate { .. } | HQMMessage::Unknown { .. } => {}
+            HQMMessage::Goal {
+                team,
+                goal_player_index,
+                assist_player_index,
+            } => {
+                let goal_name = goal_player_index.and_then(player_name);
+                let assist_name = assist_player_index.and_then(player_name);
+                writeln!(w, "Goal for {:?}, {:?}, {:?}", team, goal_name, assist_name)?;
+            }
+            HQMMessage::Chat {
+                player_index,
+                message,
+            } => {
+                let name = player_index.and_then(player_name);
+                if let Some(name) = name {
+                    writeln!(w, "{}: {}", name, message)?;
+                } else {
+                    writeln!(w, "[Server]: {}", message)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The scoreboard, every object and the messages of a single state.
+pub fn print_state<W: Write>(state: &HQMGameState, w: &mut W) -> io::Result<()> {
+    writeln!(
+        w,
+        "Packet {} Period {} Time: {}, {}-{}",
+        state.packet_number, state.period, state.time, state.red_score, state.blue_score
+    )?;
+    for (slot, object) in state.objects.iter().enumerate() {
+        match object {
+            HQMGameObject::None => {}
+            HQMGameObject::Player(skater) => writeln!(
+                w,
+                "  {} skater pos ({:.3}, {:.3}, {:.3}) stick ({:.3}, {:.3}, {:.3})",
+                slot,
+                skater.pos.x,
+                skater.pos.y,
+                skater.pos.z,
+                skater.stick_pos.x,
+                skater.stick_pos.y,
+                skater.stick_pos.z
+            )?,
+            HQMGameObject::Puck(puck) => writeln!(
+                w,
+                "  {} puck pos ({:.3}, {:.3}, {:.3})",
+                slot, puck.pos.x, puck.pos.y, puck.pos.z
+            )?,
+        }
+    }
+    print_messages(state, w)
+}
+
+/// The default output of the command line tool: the scoreboard of every state
+/// followed by its messages.
+pub fn print_replay<W: Write>(states: &[HQMGameState], w: &mut W) -> io::Result<()> {
+    for state in states.iter() {
+        writeln!(
+            w,
+            "Period {} Time: {}, {}-{}",
+            state.period, state.time, state.red_score, state.blue_score
+        )?;
+        print_messages(state, w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::HQMTeam;
+    use crate::hqm_replay::parse_replay;
+    use crate::test_util::*;
+
+    #[test]
+    fn print_replay_to_a_buffer() {
+        let mut packets = packets(3);
+        packets[0].messages = vec![join(0, "alice", Some((1, HQMTeam::Red)))];
+        packets[1].msg_pos = 1;
+        packets[1].red_score = 1;
+        packets[1].messages = vec![goal(HQMTeam::Red, Some(0), None), chat(Some(0), "gg")];
+        packets[2].msg_pos = 3;
+        packets[2].red_score = 1;
+        packets[2].messages = vec![chat(None, "Game over")];
+        let replay = parse_replay(&replay_bytes(&packets)).unwrap();
+
+        let mut out = vec![];
+        print_replay(&replay.states, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Period 1 Time: 30000, 0-0\n\
+             Period 1 Time: 29999, 1-0\n\
+             Goal for Red, Some(\"alice\"), None\n\
+             alice: gg\n\
+             Period 1 Time: 29998, 1-0\n\
+             [Server]: Game over\n"
+        );
+    }
+}
@@ -0,0 +1,1821 @@
+use crate::hqm_events::{mark_game_winner, periods, ChatLine, GoalEvent, PeriodInfo};
+use crate::hqm_game::{
+    HQMGameObject, HQMGameState, HQMMessage, HQMPuck, HQMServerPlayer, HQMSkater, HQMTeam,
+};
+use crate::hqm_index::Keyframe;
+use crate::hqm_options::ParserOptions;
+use crate::hqm_parse::{
+    convert_matrix_from_network, HQMMessageReader, HQMObjectPacket, HQMParseError, HQMPuckPacket,
+    HQMSkaterPacket, ParseWarning,
+};
+use crate::hqm_validate::score_regressions;
+use nalgebra::Point3;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+// Two little-endian u32 words precede the first packet:
+// a format version (always 0 so far) and the number of packet bytes that follow
+pub(crate) const HEADER_SIZE: usize = 8;
+// Marker, scoreboard, packet numbers, one bit per object slot and the message counts
+const MIN_PACKET_SIZE: usize = 25;
+// How far into the data to look for the first packet when detecting the header
+const HEADER_SEARCH_LIMIT: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct ReplayHeader {
+    pub version: u32,
+    // Length of the packet data after the header, as declared by the recorder
+    pub data_length: u32,
+    pub file_size: usize,
+    // Byte offset of the first packet
+    pub data_start: usize,
+    // Taken from the first packet, if there is one
+    pub period: u32,
+    pub time: u32,
+    pub red_score: u32,
+    pub blue_score: u32,
+}
+
+impl ReplayHeader {
+    /// The data length in the header is advisory, parsing always continues to the end of the
+    /// data. A mismatch is reported here instead of failing.
+    pub fn length_warning(&self) -> Option<ParseWarning> {
+        let available = self.file_size - self.data_start;
+        if self.data_length as usize != available {
+            Some(ParseWarning::LengthMismatch {
+                declared: self.data_length as usize,
+                available,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub states: Vec<HQMGameState>,
+    // One entry per state, only filled if the parser was asked to keep raw packets
+    pub raw_packets: Vec<Vec<HQMObjectPacket>>,
+    pub warnings: Vec<ParseWarning>,
+}
+
+impl IntoIterator for Replay {
+    type Item = HQMGameState;
+    type IntoIter = std::vec::IntoIter<HQMGameState>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.states.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Replay {
+    type Item = &'a HQMGameState;
+    type IntoIter = std::slice::Iter<'a, HQMGameState>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.states.iter()
+    }
+}
+
+/// Builds a replay from states, e.g. a filtered copy of another replay. There is no file
+/// behind it, so the header only has the scoreboard of the first state. Assign the original
+/// header afterwards to keep it.
+impl FromIterator<HQMGameState> for Replay {
+    fn from_iter<I: IntoIterator<Item = HQMGameState>>(iter: I) -> Self {
+        let states: Vec<HQMGameState> = iter.into_iter().collect();
+        let first = states.first();
+        let header = ReplayHeader {
+            version: 0,
+            data_length: 0,
+            file_size: 0,
+            data_start: 0,
+            period: first.map_or(0, |x| x.period),
+            time: first.map_or(0, |x| x.time),
+            red_score: first.map_or(0, |x| x.red_score),
+            blue_score: first.map_or(0, |x| x.blue_score),
+        };
+        Replay {
+            header,
+            states,
+            raw_packets: vec![],
+            warnings: vec![],
+        }
+    }
+}
+
+impl Replay {
+    pub fn iter(&self) -> std::slice::Iter<'_, HQMGameState> {
+        self.states.iter()
+    }
+
+    /// Finds the first tick at or past the given game clock reading.
+    /// The clock counts down within a period and pauses at stoppages,
+    /// so several ticks can share the same time; the earliest one is returned.
+    pub fn at_time(&self, period: u32, time: u32) -> Option<&HQMGameState> {
+        self.states
+            .iter()
+            .find(|state| state.period > period || (state.period == period && state.time <= time))
+    }
+
+    pub fn periods(&self) -> Vec<PeriodInfo> {
+        periods(&self.states)
+    }
+
+    /// Smallest box (min corner, max corner) containing every object position in the replay.
+    /// Anything far outside the rink points at a decoding problem.
+    /// `None` if there are no objects at all.
+    pub fn bounding_box(&self) -> Option<(Point3<f32>, Point3<f32>)> {
+        bounding_box(
+            self.states
+                .iter()
+                .flat_map(|state| state.objects.iter().filter_map(|object| object.pos())),
+        )
+    }
+
+    pub fn puck_bounding_box(&self) -> Option<(Point3<f32>, Point3<f32>)> {
+        bounding_box(
+            self.states
+                .iter()
+                .flat_map(|state| state.pucks().map(|(_, puck)| &puck.pos)),
+        )
+    }
+
+    /// Only skater bodies, sticks are not included.
+    pub fn skater_bounding_box(&self) -> Option<(Point3<f32>, Point3<f32>)> {
+        bounding_box(self.states.iter().flat_map(|state| {
+            state.objects.iter().filter_map(|object| match object {
+                HQMGameObject::Player(skater) => Some(&skater.pos),
+                _ => None,
+            })
+        }))
+    }
+}
+
+fn bounding_box<'a, I: Iterator<Item = &'a Point3<f32>>>(
+    mut points: I,
+) -> Option<(Point3<f32>, Point3<f32>)> {
+    let first = *points.next()?;
+    Some(points.fold((first, first), |(min, max), p| (min.inf(p), max.sup(p))))
+}
+
+/// Reads the header and the scoreboard of the first packet without decoding the rest of the replay.
+pub fn parse_header(data: &[u8]) -> Result<ReplayHeader, HQMParseError> {
+    read_header(data, HEADER_SIZE)
+}
+
+// Reads the header in the 8 bytes before `start`, where the first packet is.
+// Without room for a header, the version is 0 and all data after `start` is packet data.
+fn read_header(data: &[u8], start: usize) -> Result<ReplayHeader, HQMParseError> {
+    if data.len() < start {
+        return Err(HQMParseError::TooSmall { size: data.len() });
+    }
+    let available = data.len() - start;
+    let mut reader = HQMMessageReader::new(data);
+    let (version, data_length) = if start >= HEADER_SIZE {
+        reader.pos = start - HEADER_SIZE;
+        (reader.read_u32_aligned(), reader.read_u32_aligned())
+    } else {
+        reader.pos = start;
+        (0, available as u32)
+    };
+    let mut header = ReplayHeader {
+        version,
+        data_length,
+        file_size: data.len(),
+        data_start: start,
+        period: 0,
+        time: 0,
+        red_score: 0,
+        blue_score: 0,
+    };
+    if reader.pos < data.len() {
+        reader.read_byte_aligned();
+        let _game_over = reader.read_bits(1) == 1;
+        header.red_score = reader.read_bits(8);
+        header.blue_score = reader.read_bits(8);
+        header.time = reader.read_bits(16);
+        let _goal_message_timer = reader.read_bits(16);
+        header.period = reader.read_bits(8);
+    }
+    Ok(header)
+}
+
+// Where the first packet starts according to the options
+fn first_packet_offset(data: &[u8], options: &ParserOptions) -> Result<usize, HQMParseError> {
+    if !options.detect_header {
+        return Ok(options.header_skip);
+    }
+    (0..data.len().min(HEADER_SEARCH_LIMIT))
+        .find(|&start| data[start] == 5 && looks_like_packet(data, start, options))
+        .ok_or(HQMParseError::NoPacketFound)
+}
+
+// Reads a packet at `start` without decoding the objects, and checks that
+// another packet or the end of the data follows
+fn looks_like_packet(data: &[u8], start: usize, options: &ParserOptions) -> bool {
+    let options = options.clone().lenient(false);
+    let mut reader = HQMMessageReader::new(data);
+    reader.pos = start;
+    reader.read_byte_aligned();
+    let _game_over = reader.read_bits(1);
+    let _red_score = reader.read_bits(8);
+    let _blue_score = reader.read_bits(8);
+    let _time = reader.read_bits(16);
+    let _goal_message_timer = reader.read_bits(16);
+    let _period = reader.read_bits(8);
+    skip_objects(&mut reader, &options);
+    let message_num = reader.read_bits(16);
+    let _msg_pos = reader.read_bits(16);
+    for _ in 0..message_num {
+        if read_message(&mut reader, &options).is_err() || reader.is_past_end() {
+            return false;
+        }
+    }
+    if reader.is_past_end() {
+        return false;
+    }
+    reader.consume_tick_terminator();
+    reader.pos == data.len() || data.get(reader.pos) == Some(&5)
+}
+
+pub fn parse_replay(data: &[u8]) -> Result<Replay, HQMParseError> {
+    ReplayParser::new(data).parse()
+}
+
+/// Parses a file holding several replays written back to back, e.g. by archival tools.
+/// A replay ends where the next byte isn't a packet marker or at the end of the data.
+/// The next replay starts at the first following offset with a standard header: a version
+/// word of 0, then a length word, then something that reads as a packet. Junk between
+/// replays is skipped, and so is anything after the last replay that doesn't look like one.
+/// A file with a single replay gives a single element.
+pub fn parse_all(data: &[u8]) -> Result<Vec<Replay>, HQMParseError> {
+    let mut res = vec![];
+    let mut start = 0;
+    loop {
+        let rest = &data[start..];
+        let mut states = ReplayParser::new(rest).states()?;
+        let mut history = vec![];
+        while let Some(state) = states.next() {
+            history.push(state?);
+            let pos = states.position();
+            if rest.get(pos) != Some(&5) {
+                break;
+            }
+        }
+        let end = states.position().min(rest.len());
+        let header = parse_header(&rest[..end])?;
+        let mut warnings: Vec<ParseWarning> = header.length_warning().into_iter().collect();
+        warnings.extend(score_regressions(&history));
+        res.push(Replay {
+            warnings,
+            header,
+            states: history,
+            raw_packets: vec![],
+        });
+        match (start + end..data.len()).find(|&pos| is_header_at(data, pos)) {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+    Ok(res)
+}
+
+fn is_header_at(data: &[u8], pos: usize) -> bool {
+    let first_packet = pos + HEADER_SIZE;
+    data.len() > first_packet
+        && data[pos..pos + 4] == [0; 4]
+        && data[first_packet] == 5
+        && looks_like_packet(data, first_packet, &ParserOptions::default())
+}
+
+pub struct ReplayParser<'a> {
+    data: &'a [u8],
+    options: ParserOptions,
+}
+
+impl<'a> ReplayParser<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self::with_options(data, ParserOptions::default())
+    }
+
+    pub fn with_options(data: &'a [u8], options: ParserOptions) -> Self {
+        ReplayParser { data, options }
+    }
+
+    pub fn parse(self) -> Result<Replay, HQMParseError> {
+        let start = first_packet_offset(self.data, &self.options)?;
+        let header = read_header(self.data, start)?;
+        let data = self.data;
+
+        let mut states = self.states()?;
+        let mut history = vec![];
+        let mut raw_packets = vec![];
+        while let Some(res) = states.next_with_raw_packets() {
+            let (state, raw) = res?;
+            if let Some(raw) = raw {
+                raw_packets.push(raw);
+            }
+            history.push(state);
+        }
+
+        let mut warnings: Vec<ParseWarning> = header.length_warning().into_iter().collect();
+        if states.position() < data.len() {
+            warnings.push(ParseWarning::TrailingData {
+                offset: states.position(),
+                length: data.len() - states.position(),
+            });
+        }
+        warnings.extend(score_regressions(&history));
+        Ok(Replay {
+            warnings,
+            header,
+            states: history,
+            raw_packets,
+        })
+    }
+
+    /// Decodes the replay one frame at a time without converting the objects. The integer
+    /// object packets are what the delta decoding needs anyway, so this skips all float
+    /// work: the divisions for positions and, by far the most expensive part, rebuilding
+    /// the rotation matrices. A skater packet is also 48 bytes against 104 for a skater,
+    /// so keeping frames around takes less memory. Convert the frames that are needed
+    /// with `convert_object_packets`.
+    pub fn raw_frames(self) -> Result<RawFrames<'a>, HQMParseError> {
+        let mut options = self.options.keep_raw_packets(true);
+        options.convert_objects = false;
+        let states = ReplayParser::with_options(self.data, options).states()?;
+        Ok(RawFrames { states })
+    }
+
+    /// Decodes the replay one state at a time, so that callers that don't need
+    /// all states at once can keep memory usage down.
+    pub fn states(self) -> Result<ReplayStates<'a>, HQMParseError> {
+        let start = first_packet_offset(self.data, &self.options)?;
+        read_header(self.data, start)?;
+        Ok(ReplayStates::resume(
+            self.data,
+            start,
+            ParserState::new(self.options),
+        ))
+    }
+}
+
+/// Parser for many replays in a row. The allocations made while decoding, like the table of
+/// earlier packets used for delta decoding, are kept between replays instead of being made
+/// again for every file. `parse_replay` is simpler when only one replay is parsed, and also
+/// gives the header and warnings.
+pub struct Parser {
+    options: ParserOptions,
+    // Taken while a replay is decoded
+    state: Option<ParserState>,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::with_options(ParserOptions::default())
+    }
+
+    pub fn with_options(options: ParserOptions) -> Self {
+        Parser {
+            options,
+            state: None,
+        }
+    }
+
+    /// Replaces the contents of `out` with the states of the replay in `data`. On error,
+    /// `out` holds the states decoded before the error.
+    pub fn parse_into(
+        &mut self,
+        data: &[u8],
+        out: &mut Vec<HQMGameState>,
+    ) -> Result<(), HQMParseError> {
+        out.clear();
+        let start = first_packet_offset(data, &self.options)?;
+        read_header(data, start)?;
+        let mut state = self
+            .state
+            .take()
+            .unwrap_or_else(|| ParserState::new(self.options.clone()));
+        state.reset();
+        let mut states = ReplayStates::resume(data, start, state);
+        let res = states
+            .by_ref()
+            .try_for_each(|state| state.map(|state| out.push(state)));
+        self.state = Some(states.parser_state);
+        res
+    }
+}
+
+/// A frame with its objects as they were sent, before conversion to floats.
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    // Everything but the objects, which are left empty
+    pub state: HQMGameState,
+    pub object_packets: Vec<HQMObjectPacket>,
+}
+
+/// Frames from `ReplayParser::raw_frames`.
+pub struct RawFrames<'a> {
+    states: ReplayStates<'a>,
+}
+
+impl Iterator for RawFrames<'_> {
+    type Item = Result<RawFrame, HQMParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.states.next_with_raw_packets().map(|res| {
+            res.map(|(state, object_packets)| RawFrame {
+                state,
+                object_packets: object_packets.unwrap_or_default(),
+            })
+        })
+    }
+}
+
+type StateWithRawPackets = (HQMGameState, Option<Vec<HQMObjectPacket>>);
+
+// The bytes from `pos` on can't be a packet, like a footer some recorders append after the
+// last one. `pos` must be before the end of the data.
+fn at_footer(data: &[u8], pos: usize) -> bool {
+    let rest = &data[pos..];
+    rest[0] != 5 || rest.len() < MIN_PACKET_SIZE
+}
+
+pub struct ReplayStates<'a> {
+    reader: HQMMessageReader<'a>,
+    data: &'a [u8],
+    parser_state: ParserState,
+    done: bool,
+}
+
+impl<'a> ReplayStates<'a> {
+    // Continues decoding at `pos` with an already set up parser state
+    pub(crate) fn resume(data: &'a [u8], pos: usize, parser_state: ParserState) -> Self {
+        let mut reader = HQMMessageReader::new(data);
+        reader.pos = pos;
+        ReplayStates {
+            reader,
+            data,
+            parser_state,
+            done: false,
+        }
+    }
+
+    // Byte offset of the next packet
+    pub(crate) fn position(&self) -> usize {
+        self.reader.pos
+    }
+
+    pub(crate) fn parser_state(&self) -> &ParserState {
+        &self.parser_state
+    }
+
+    // Like next, but also returns the object packets if the options ask to keep them
+    fn next_with_raw_packets(&mut self) -> Option<Result<StateWithRawPackets, HQMParseError>> {
+        if self.done || self.reader.pos >= self.data.len() {
+            return None;
+        }
+        if !self.parser_state.options.strict && at_footer(self.data, self.reader.pos) {
+            self.done = true;
+            return None;
+        }
+        let packet = match self.parser_state.read_packet(&mut self.reader) {
+            Ok(packet) => packet,
+            // Same as a packet that runs past the end below
+            Err(HQMParseError::UnexpectedEof) if self.parser_state.options.lenient => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        self.reader.consume_tick_terminator();
+        if self.reader.pos > self.data.len() {
+            if self.parser_state.options.strict {
+                self.done = true;
+                return Some(Err(HQMParseError::UnexpectedEof));
+            } else if self.parser_state.options.lenient {
+                self.done = true;
+                return None;
+            }
+        }
+        let raw = if self.parser_state.options.keep_raw_packets {
+            Some(packet.object_packets.clone())
+        } else {
+            None
+        };
+        Some(Ok((self.parser_state.commit(packet), raw)))
+    }
+}
+
+impl<'a> Iterator for ReplayStates<'a> {
+    type Item = Result<HQMGameState, HQMParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_raw_packets()
+            .map(|res| res.map(|(state, _)| state))
+    }
+}
+
+/// Decodes only goals and chat, for quickly indexing many replays.
+/// Object data is skipped over without being decoded, so no geometry is available
+/// and the old packets needed for delta decoding don't have to be kept around.
+/// Without geometry, `last_touch`, `is_own_goal` and `empty_net` on the goals are never set.
+pub fn parse_events_only(data: &[u8]) -> Result<(Vec<GoalEvent>, Vec<ChatLine>), HQMParseError> {
+    parse_events_only_with(data, &ParserOptions::default())
+}
+
+/// Like `parse_events_only`, finding the first packet and reading messages as the options say.
+pub fn parse_events_only_with(
+    data: &[u8],
+    options: &ParserOptions,
+) -> Result<(Vec<GoalEvent>, Vec<ChatLine>), HQMParseError> {
+    let start = first_packet_offset(data, options)?;
+    read_header(data, start)?;
+
+    let data_len = data.len();
+    let mut reader = HQMMessageReader::new(data);
+    reader.pos = start;
+
+    let mut goals = vec![];
+    let mut chat = vec![];
+    let mut player_names: Vec<Option<String>> = vec![None; 63];
+    let mut current_msg_pos = 0;
+    let mut tick = 0;
+    let mut final_score = (0, 0);
+    while reader.pos < data_len && !at_footer(data, reader.pos) {
+        reader.read_byte_aligned();
+        let _game_over = reader.read_bits(1) == 1;
+        let red_score = reader.read_bits(8);
+        let blue_score = reader.read_bits(8);
+        let time = reader.read_bits(16);
+        let _goal_message_timer = reader.read_bits(16);
+        let period = reader.read_bits(8);
+        final_score = (red_score, blue_score);
+
+        skip_objects(&mut reader, options);
+
+        let message_num = reader.read_bits(16);
+        let msg_pos = reader.read_bits(16);
+        let (goals_before, chat_before) = (goals.len(), chat.len());
+        for i in 0..message_num {
+            let msg = read_message(&mut reader, options)?;
+            if !is_new_message(msg_pos + i, current_msg_pos) {
+                continue;
+            }
+            match msg {
+                HQMMessage::PlayerUpdate {
+                    player_name,
+                    player_index,
+                    in_server,
+                    ..
+                } => {
+                    player_names[player_index] = if in_server { Some(player_name) } else { None };
+                }
+                HQMMessage::Goal {
+                    team,
+                    goal_player_index,
+                    assist_player_index,
+                } => goals.push(GoalEvent {
+                    tick,
+                    period,
+                    time,
+                    team,
+                    goal_player_index,
+                    goal_player_name: None,
+                    assist_player_index,
+                    assist_player_name: None,
+                    red_score,
+                    blue_score,
+                    is_game_winner: false,
+                    last_touch: None,
+                    is_own_goal: false,
+                    empty_net: false,
+                }),
+                HQMMessage::Chat {
+                    player_index,
+                    message,
+                } => chat.push(ChatLine {
+                    tick,
+                    period,
+                    time,
+                    player_index,
+                    player_name: None,
+                    message,
+                }),
+                HQMMessage::Unknown { .. } => {}
+            }
+        }
+        current_msg_pos = next_message_pos(msg_pos, message_num);
+
+        // Names are resolved against the roster at the end of the packet, like goal_events does
+        let name = |i: usize| player_names.get(i).cloned().flatten();
+        for goal in goals[goals_before..].iter_mut() {
+            goal.goal_player_name = goal.goal_player_index.and_then(name);
+            goal.assist_player_name = goal.assist_player_index.and_then(name);
+        }
+        for line in chat[chat_before..].iter_mut() {
+            line.player_name = line.player_index.and_then(name);
+        }
+
+        reader.consume_tick_terminator();
+        tick += 1;
+    }
+    mark_game_winner(&mut goals, final_score.0, final_score.1);
+    Ok((goals, chat))
+}
+
+/// Parses a replay that is still being written, like `tail -f`.
+pub struct StreamingParser {
+    state: ParserState,
+    // Where the next packet starts, None until the first packet has been found
+    pos: Option<usize>,
+}
+
+impl Default for StreamingParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamingParser {
+    pub fn new() -> Self {
+        Self::with_options(ParserOptions::default())
+    }
+
+    pub fn with_options(options: ParserOptions) -> Self {
+        StreamingParser {
+            pos: (!options.detect_header).then_some(options.header_skip),
+            state: ParserState::new(options),
+        }
+    }
+
+    /// `data` is everything written so far. Returns the frames completed since the last call,
+    /// a packet cut off at the end of `data` is left until more bytes are available.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<HQMGameState>, HQMParseError> {
+        let mut res = vec![];
+        if data.len() < HEADER_SIZE {
+            return Ok(res);
+        }
+        let start = match self.pos {
+            Some(pos) => pos,
+            None => match first_packet_offset(data, &self.state.options) {
+                Ok(start) => start,
+                // The first packet may not have been written completely yet
+                Err(HQMParseError::NoPacketFound) if data.len() < HEADER_SEARCH_LIMIT => {
+                    return Ok(res)
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        self.pos = Some(start);
+        let mut reader = HQMMessageReader::new(data);
+        reader.pos = start;
+        while reader.pos < data.len() {
+            let packet = match self.state.read_packet(&mut reader) {
+                Ok(packet) => packet,
+                // Garbage read past the end, wait for the rest of the packet
+                Err(_) if reader.is_past_end() => break,
+                Err(HQMParseError::UnexpectedEof) => break,
+                Err(e) => return Err(e),
+            };
+            reader.consume_tick_terminator();
+            if reader.pos > data.len() {
+                // The reader ran past the end, so the packet is incomplete
+                break;
+            }
+            self.pos = Some(reader.pos);
+            res.push(self.state.commit(packet));
+        }
+        Ok(res)
+    }
+}
+
+/// Parses and joins the two files of a game that was recorded in two parts, e.g. from before
+/// and after the recorder reconnected. An error tells which of the two failed to parse. The
+/// warnings, including whether `b` picks up where `a` ended, are only kept by
+/// `join_replays`.
+pub fn concat_replays(a: &[u8], b: &[u8]) -> Result<Vec<HQMGameState>, HQMParseError> {
+    let part = |part: usize, data: &[u8]| {
+        parse_replay(data).map_err(|e| HQMParseError::SplitPart {
+            part,
+            error: Box::new(e),
+        })
+    };
+    Ok(join_replays(part(1, a)?, part(2, b)?).states)
+}
+
+/// Joins two recordings of the same game, like `concat_replays` does after parsing them.
+/// Packet numbers and message positions of `b` are shifted to continue from the end of `a`,
+/// and tick based warnings of `b` are moved past the ticks of `a`. The header is the one
+/// of `a`. If `b` doesn't pick up where `a` ended, a `SplitMismatch` warning is added.
+pub fn join_replays(a: Replay, b: Replay) -> Replay {
+    let Replay {
+        header,
+        mut states,
+        mut raw_packets,
+        mut warnings,
+    } = a;
+    let offset = states.len();
+    if let (Some(last), Some(first)) = (states.last(), b.states.first()) {
+        let from = (last.period, last.red_score, last.blue_score);
+        let to = (first.period, first.red_score, first.blue_score);
+        if to.0 < from.0 || to.1 < from.1 || to.2 < from.2 {
+            warnings.push(ParseWarning::SplitMismatch {
+                tick: offset,
+                from,
+                to,
+            });
+        }
+    }
+    let packet_base = states.last().map_or(0, |x| x.packet_number.wrapping_add(1));
+    let first_packet = b.states.first().map_or(0, |x| x.packet_number);
+    let msg_base = states
+        .iter()
+        .map(|x| x.msg_pos + x.message_num)
+        .max()
+        .unwrap_or(0);
+    states.extend(b.states.into_iter().map(|mut state| {
+        state.packet_number = state
+            .packet_number
+            .wrapping_sub(first_packet)
+            .wrapping_add(packet_base);
+        state.msg_pos += msg_base;
+        state
+    }));
+    // Raw packets are only kept if both sides have them, otherwise they wouldn't line up
+    if raw_packets.is_empty() || b.raw_packets.is_empty() {
+        raw_packets.clear();
+    } else {
+        raw_packets.extend(b.raw_packets);
+    }
+    warnings.extend(b.warnings.into_iter().map(|warning| match warning {
+        ParseWarning::ScoreRegression { tick, from, to } => ParseWarning::ScoreRegression {
+            tick: tick + offset,
+            from,
+            to,
+        },
+        ParseWarning::SplitMismatch { tick, from, to } => ParseWarning::SplitMismatch {
+            tick: tick + offset,
+            from,
+            to,
+        },
+        other => other,
+    }));
+    Replay {
+        header,
+        states,
+        raw_packets,
+        warnings,
+    }
+}
+
+/// Reads and parses a replay file. With the `memmap2` feature the file is memory mapped
+/// instead of read into memory, falling back to reading it if mapping fails. Like
+/// `parse_replay`, warnings are returned with the states.
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Replay, HQMParseError> {
+    let path = path.as_ref();
+    #[cfg(feature = "memmap2")]
+    {
+        let file = File::open(path)?;
+        // Safety: the map is only read while parsing. If another process truncates the file
+        // in the meantime the read faults, which is the usual caveat of mapping files.
+        if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+            return parse_replay(&map);
+        }
+    }
+    let data = std::fs::read(path)?;
+    parse_replay(&data)
+}
+
+/// Follows a replay file while it is being recorded, calling `f` for each new frame.
+/// Returns once a frame with the game over flag has been seen.
+pub fn follow_file<P: AsRef<Path>, F: FnMut(&HQMGameState)>(
+    path: P,
+    poll_interval: Duration,
+    mut f: F,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut data = vec![];
+    let mut parser = StreamingParser::new();
+    loop {
+        file.read_to_end(&mut data)?;
+        for state in parser.feed(&data)? {
+            f(&state);
+            if state.game_over {
+                return Ok(());
+            }
+        }
+        std::thread::sleep(poll_interval);
+    }
+}
+
+// Messages are numbered consecutively, and every packet carries the window
+// [msg_pos, msg_pos + message_num) of the most recent ones, so the same message is
+// usually sent in several packets. A message is only new if its number is at or past
+// the end of the windows seen so far.
+fn is_new_message(msg_pos_of_this_message: u32, current_msg_pos: u32) -> bool {
+    msg_pos_of_this_message >= current_msg_pos
+}
+
+// The end of the window of the latest packet. Not the furthest end seen so far, so if the
+// 16-bit position wraps around or the server resets it, messages are picked up again after
+// one window instead of being dropped for the rest of the replay.
+fn next_message_pos(msg_pos: u32, message_num: u32) -> u32 {
+    msg_pos + message_num
+}
+
+pub(crate) struct DecodedPacket {
+    state: HQMGameState,
+    object_packets: Vec<HQMObjectPacket>,
+    // Only set if a player update changed the list
+    player_list: Option<Vec<Option<HQMServerPlayer>>>,
+    next_msg_pos: u32,
+}
+
+pub(crate) struct ParserState {
+    pub(crate) options: ParserOptions,
+    // You probably don't need to save all packets,
+    // just the most recent 64 or so. Nonetheless, it is easier to just keep all of them for now
+    // The only issue will be more RAM usage than necessary
+    old_saved_packets: HashMap<u32, Vec<HQMObjectPacket>>,
+    player_list: Vec<Option<HQMServerPlayer>>,
+    current_msg_pos: u32,
+    // Last goal message and the score when it was seen, for collapsing repeats
+    last_goal: Option<(HQMMessage, u32, u32)>,
+}
+
+impl ParserState {
+    pub(crate) fn new(options: ParserOptions) -> Self {
+        ParserState {
+            options,
+            old_saved_packets: HashMap::new(),
+            player_list: vec![None; 63],
+            current_msg_pos: 0,
+            last_goal: None,
+        }
+    }
+
+    // Back to the state before the first packet, keeping the allocations
+    pub(crate) fn reset(&mut self) {
+        self.old_saved_packets.clear();
+        self.player_list.fill(None);
+        self.current_msg_pos = 0;
+        self.last_goal = None;
+    }
+
+    // Everything needed to continue decoding from the current position except the earlier
+    // packets that later packets are delta coded against: the roster and the message window.
+    pub(crate) fn keyframe(&self, frame: usize, offset: usize) -> Keyframe {
+        Keyframe {
+            frame,
+            offset,
+            packets: vec![],
+            player_list: self.player_list.clone(),
+            current_msg_pos: self.current_msg_pos,
+        }
+    }
+
+    // The object packets decoded so far with the given packet numbers, if they were seen
+    pub(crate) fn saved_packets(&self, packet_numbers: &[u32]) -> Vec<(u32, Vec<HQMObjectPacket>)> {
+        packet_numbers
+            .iter()
+            .filter_map(|x| Some((*x, self.old_saved_packets.get(x)?.clone())))
+            .collect()
+    }
+
+    pub(crate) fn from_keyframe(options: ParserOptions, keyframe: &Keyframe) -> Self {
+        ParserState {
+            options,
+            old_saved_packets: keyframe.packets.iter().cloned().collect(),
+            player_list: keyframe.player_list.clone(),
+            current_msg_pos: keyframe.current_msg_pos,
+            last_goal: None,
+        }
+    }
+
+    // Decodes one packet without changing the parser state, so that an incomplete packet
+    // can be thrown away. Call commit to apply it.
+    pub(crate) fn read_packet(
+        &self,
+        reader: &mut HQMMessageReader,
+    ) -> Result<DecodedPacket, HQMParseError> {
+        let marker_pos = reader.pos;
+        let marker = reader.read_byte_aligned(); // Should be 5, only checked in strict mode
+        if self.options.strict && marker != 5 {
+            return Err(HQMParseError::InvalidPacketMarker {
+                pos: marker_pos,
+                marker,
+            });
+        }
+        let game_over = reader.read_bits(1) == 1;
+        let red_score = reader.read_bits(8);
+        let blue_score = reader.read_bits(8);
+        let time = reader.read_bits(16);
+        let goal_message_timer = reader.read_bits(16);
+        let period = reader.read_bits(8);
+
+        let (object_packets, packet_number) =
+            read_objects(reader, &self.old_saved_packets, &self.options)?;
+        let objects = if self.options.convert_objects {
+            convert_object_packets(&object_packets, &self.options)
+        } else {
+            vec![]
+        };
+
+        let message_num = reader.read_bits(16);
+        let msg_pos = reader.read_bits(16);
+        let mut messages_in_this_packet = vec![];
+        let mut player_list: Option<Vec<Option<HQMServerPlayer>>> = None;
+        for i in 0..message_num {
+            let msg_pos_of_this_message = msg_pos + i;
+            let msg = read_message(reader, &self.options)?;
+            // The unknown message swallowed the rest of the packet, whether it is new or a
+            // repeat. Step back so that skipping the end of the packet lands on the next packet
+            // marker, and don't read anything more from this packet.
+            let unknown = matches!(msg, HQMMessage::Unknown { .. });
+
+            if is_new_message(msg_pos_of_this_message, self.current_msg_pos)
+                && !(self.options.collapse_goal_repeats
+                    && self.last_goal == Some((msg.clone(), red_score, blue_score)))
+            {
+                if let HQMMessage::PlayerUpdate {
+                    ref player_name,
+                    object,
+                    player_index,
+                    in_server,
+                } = msg
+                {
+                    let list = player_list.get_or_insert_with(|| self.player_list.clone());
+                    if in_server {
+                        list[player_index] = Some(HQMServerPlayer {
+                            name: player_name.clone(),
+                            team_and_skater: object,
+                        })
+                    } else {
+                        list[player_index] = None;
+                    }
+                }
+                messages_in_this_packet.push(msg);
+            }
+
+            if unknown {
+                reader.pos -= 1;
+                break;
+            }
+        }
+
+        let state = HQMGameState {
+            packet_number,
+            red_score,
+            blue_score,
+            period,
+            game_over,
+            time,
+            goal_message_timer,
+            objects,
+            player_list: vec![],
+            messages_in_this_packet,
+            msg_pos,
+            message_num,
+        };
+        Ok(DecodedPacket {
+            state,
+            object_packets,
+            player_list,
+            next_msg_pos: next_message_pos(msg_pos, message_num),
+        })
+    }
+
+    pub(crate) fn commit(&mut self, packet: DecodedPacket) -> HQMGameState {
+        let mut state = packet.state;
+        if let Some(player_list) = packet.player_list {
+            self.player_list = player_list;
+        }
+        state.player_list = self.player_list.clone();
+        self.current_msg_pos = packet.next_msg_pos;
+        for msg in state.messages_in_this_packet.iter() {
+            if let HQMMessage::Goal { .. } = msg {
+                self.last_goal = Some((msg.clone(), state.red_score, state.blue_score));
+            }
+        }
+        self.old_saved_packets
+            .insert(state.packet_number, packet.object_packets);
+        state
+    }
+}
+
+fn read_string(
+    bytes: Vec<u8>,
+    options: &ParserOptions,
+    pos: usize,
+) -> Result<String, HQMParseError> {
+    let s = if options.lossy_strings {
+        String::from_utf8_lossy(&bytes).into_owned()
+    } else {
+        String::from_utf8(bytes).map_err(|_| HQMParseError::InvalidString { pos })?
+    };
+    Ok(s.trim_matches(char::from(0)).to_string())
+}
+
+fn read_message(
+    reader: &mut HQMMessageReader,
+    options: &ParserOptions,
+) -> Result<HQMMessage, HQMParseError> {
+    let message_type = reader.read_bits(6);
+    if message_type == 0 {
+        // Player update
+        let player_index = reader.read_bits(6) as usize;
+        let in_server = reader.read_bits(1) == 1;
+        let team = match reader.read_bits(2) {
+            0 => Some(HQMTeam::Red),
+            1 => Some(HQMTeam::Blue),
+            _ => None,
+        };
+        let object_index = match reader.read_bits(6) {
+            0x3F => None,
+            x => Some(x as usize),
+        };
+        let object = object_index.zip(team);
+        let string_pos = reader.pos;
+        let mut bytes = vec![];
+        for _ in 0..options.string_length {
+            bytes.push(reader.read_bits(7) as u8);
+        }
+        Ok(HQMMessage::PlayerUpdate {
+            player_name: read_string(bytes, options, string_pos)?,
+            object,
+            player_index,
+            in_server,
+        })
+    } else if message_type == 1 {
+        // Goal
+        let team = match reader.read_bits(2) {
+            0 => HQMTeam::Red,
+            _ => HQMTeam::Blue,
+        };
+        let goal_player_index = match reader.read_bits(6) {
+            0x3F => None,
+            x => Some(x as usize),
+        };
+        let assist_player_index = match reader.read_bits(6) {
+            0x3F => None,
+            x => Some(x as usize),
+        };
+        Ok(HQMMessage::Goal {
+            team,
+            goal_player_index,
+            assist_player_index,
+        })
+    } else if message_type == 2 {
+        let player_index = match reader.read_bits(6) {
+            0x3F => None,
+            x => Some(x as usize),
+        };
+        let size = reader.read_bits(6);
+        // A corrupt size near the end would otherwise read past it and pad the line with NULs
+        if size as usize * 7 > reader.bits_remaining() {
+            return Err(HQMParseError::UnexpectedEof);
+        }
+        let string_pos = reader.pos;
+        let mut bytes = vec![];
+        for _ in 0..size {
+            bytes.push(reader.read_bits(7) as u8);
+        }
+        Ok(HQMMessage::Chat {
+            player_index,
+            message: read_string(bytes, options, string_pos)?,
+        })
+    } else if options.lenient {
+        // Messages don't carry their length, so there is no telling where an unknown one ends.
+        // Keep everything up to the next byte that looks like a packet marker.
+        let raw_bits = reader.read_until_byte(5);
+        Ok(HQMMessage::Unknown {
+            message_type,
+            raw_bits,
+        })
+    } else {
+        Err(HQMParseError::UnknownMessageType {
+            pos: reader.pos,
+            message_type,
+        })
+    }
+}
+
+// The number of the earlier packet that the objects of the packet at `offset` are delta coded
+// against. It follows the scoreboard, which takes 65 bits, and the packet's own number.
+pub(crate) fn delta_reference(data: &[u8], offset: usize) -> u32 {
+    let mut reader = HQMMessageReader::new(data);
+    reader.pos = offset + 13;
+    reader.read_u32_aligned()
+}
+
+fn skip_objects(reader: &mut HQMMessageReader, options: &ParserOptions) {
+    let _current_packet_num = reader.read_u32_aligned();
+    let _previous_packet_num = reader.read_u32_aligned();
+    for _ in 0..options.max_objects {
+        if reader.read_bits(1) == 1 {
+            let object_type = reader.read_bits(2);
+            for b in [17, 17, 17, 31, 31] {
+                reader.skip_pos(b);
+            }
+            if object_type == 0 {
+                for b in [13, 13, 13, 25, 25, 16, 16] {
+                    reader.skip_pos(b);
+                }
+            }
+        }
+    }
+}
+
+fn read_objects(
+    reader: &mut HQMMessageReader,
+    history: &HashMap<u32, Vec<HQMObjectPacket>>,
+    options: &ParserOptions,
+) -> Result<(Vec<HQMObjectPacket>, u32), HQMParseError> {
+    let current_packet_num = reader.read_u32_aligned();
+    let previous_packet_num = reader.read_u32_aligned();
+
+    let find_old: Option<&[HQMObjectPacket]> =
+        history.get(&previous_packet_num).map(|x| x.as_slice());
+
+    let mut packets = vec![];
+
+    for i in 0..options.max_objects {
+        let is_object = reader.read_bits(1) == 1;
+        let packet = if is_object {
+            let old_object_in_this_slot = find_old.and_then(|x| x.get(i));
+            let (type_pos, type_bit) = (reader.pos, reader.bit_pos);
+            let object_type = reader.read_bits(2);
+            if object_type == 0 {
+                let old_skater = match &old_object_in_this_slot {
+                    Some(HQMObjectPacket::Skater(skater)) => Some(skater),
+                    _ => None,
+                };
+                let old_pos = old_skater.map(|x| x.pos);
+                let old_rot = old_skater.map(|x| x.rot);
+
+                let x = reader.read_pos(17, old_pos.map(|x| x.0), options.position_clamp)?;
+                let y = reader.read_pos(17, old_pos.map(|x| x.1), options.position_clamp)?;
+                let z = reader.read_pos(17, old_pos.map(|x| x.2), options.position_clamp)?;
+                let r1 = reader.read_pos(31, old_rot.map(|x| x.0), options.position_clamp)?;
+                let r2 = reader.read_pos(31, old_rot.map(|x| x.1), options.position_clamp)?;
+
+                let stick_x = reader.read_pos(
+                    13,
+                    old_skater.map(|x| x.stick_pos.0),
+                    options.position_clamp,
+                )?;
+                let stick_y = reader.read_pos(
+                    13,
+                    old_skater.map(|x| x.stick_pos.1),
+                    options.position_clamp,
+                )?;
+                let stick_z = reader.read_pos(
+                    13,
+                    old_skater.map(|x| x.stick_pos.2),
+                    options.position_clamp,
+                )?;
+
+                let stick_r1 = reader.read_pos(
+                    25,
+                    old_skater.map(|x| x.stick_rot.0),
+                    options.position_clamp,
+                )?;
+                let stick_r2 = reader.read_pos(
+                    25,
+                    old_skater.map(|x| x.stick_rot.1),
+                    options.position_clamp,
+                )?;
+
+                let body_turn =
+                    reader.read_pos(16, old_skater.map(|x| x.body_turn), options.position_clamp)?;
+                let body_lean =
+                    reader.read_pos(16, old_skater.map(|x| x.body_lean), options.position_clamp)?;
+
+                HQMObjectPacket::Skater(HQMSkaterPacket {
+                    pos: (x, y, z),
+                    rot: (r1, r2),
+                    stick_pos: (stick_x, stick_y, stick_z),
+                    stick_rot: (stick_r1, stick_r2),
+                    body_turn,
+                    body_lean,
+                })
+                // Player
+            } else if object_type == 1 {
+                // Puck. There is only one kind: the packet has no radius, mass or variant
+                // field, just position and rotation. Modes with special pucks can only tell
+                // them apart by slot or behaviour, and the 2 bit type leaves room for a new
+                // object type if the protocol ever grows one.
+                let old_puck = match &old_object_in_this_slot {
+                    Some(HQMObjectPacket::Puck(puck)) => Some(puck),
+                    _ => None,
+                };
+
+                let old_pos = old_puck.map(|x| x.pos);
+                let old_rot = old_puck.map(|x| x.rot);
+
+                let x = reader.read_pos(17, old_pos.map(|x| x.0), options.position_clamp)?;
+                let y = reader.read_pos(17, old_pos.map(|x| x.1), options.position_clamp)?;
+                let z = reader.read_pos(17, old_pos.map(|x| x.2), options.position_clamp)?;
+                let r1 = reader.read_pos(31, old_rot.map(|x| x.0), options.position_clamp)?;
+                let r2 = reader.read_pos(31, old_rot.map(|x| x.1), options.position_clamp)?;
+
+                HQMObjectPacket::Puck(HQMPuckPacket {
+                    pos: (x, y, z),
+                    rot: (r1, r2),
+                })
+            } else {
+                return Err(HQMParseError::UnknownObjectType {
+                    pos: type_pos,
+                    bit: type_bit,
+                    object_type,
+                });
+            }
+        } else {
+            HQMObjectPacket::None
+        };
+        packets.push(packet);
+    }
+
+    Ok((packets, current_packet_num))
+}
+
+/// Converts object packets to positions in meters and rotation matrices, like the parser
+/// does for every frame. Use with `RawFrame`s to convert only the frames that are needed.
+pub fn convert_object_packets(
+    packets: &[HQMObjectPacket],
+    options: &ParserOptions,
+) -> Vec<HQMGameObject> {
+    packets
+        .iter()
+        .map(|x| match x {
+            HQMObjectPacket::None => HQMGameObject::None,
+            HQMObjectPacket::Puck(packet) => {
+                let pos = Point3::new(
+                    packet.pos.0 as i32 as f32 / options.position_scale,
+                    packet.pos.1 as i32 as f32 / options.position_scale,
+                    packet.pos.2 as i32 as f32 / options.position_scale,
+                );
+                let rot = convert_matrix_from_network(31, packet.rot.0, packet.rot.1);
+
+                HQMGameObject::Puck(HQMPuck { pos, rot })
+            }
+            HQMObjectPacket::Skater(packet) => {
+                let pos = Point3::new(
+                    packet.pos.0 as i32 as f32 / options.position_scale,
+                    packet.pos.1 as i32 as f32 / options.position_scale,
+                    packet.pos.2 as i32 as f32 / options.position_scale,
+                );
+                let rot = convert_matrix_from_network(31, packet.rot.0, packet.rot.1);
+                let stick_pos = Point3::new(
+                    (packet.stick_pos.0 as i32 as f32 / options.position_scale) + pos.x - 4.0,
+                    (packet.stick_pos.1 as i32 as f32 / options.position_scale) + pos.y - 4.0,
+                    (packet.stick_pos.2 as i32 as f32 / options.position_scale) + pos.z - 4.0,
+                );
+                let stick_rot =
+                    convert_matrix_from_network(25, packet.stick_rot.0, packet.stick_rot.1);
+                HQMGameObject::Player(HQMSkater {
+                    pos,
+                    rot,
+                    stick_pos,
+                    stick_rot,
+                    body_turn: (packet.body_turn as i32 as f32 - 16384.0) / 8192.0,
+                    body_lean: (packet.body_lean as i32 as f32 - 16384.0) / 8192.0,
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_events::{chat_lines, goal_events};
+    use crate::hqm_parse::PositionClampPolicy;
+    use crate::test_util::*;
+
+    #[test]
+    fn non_default_options() {
+        let mut packets = packets(3);
+        packets[0].objects = vec![puck_packet(10.0, 0.5, 20.0)];
+        packets[1].objects = vec![puck_packet(10.0, 0.5, 20.0)];
+        packets[2].objects = vec![puck_packet(10.0, 0.5, 20.0)];
+        let data = replay_bytes(&packets);
+
+        let options = ParserOptions::default()
+            .strict(true)
+            .keep_raw_packets(true)
+            .position_scale(512.0);
+        let replay = ReplayParser::with_options(&data, options).parse().unwrap();
+        assert_eq!(replay.states.len(), 3);
+        assert_eq!(replay.raw_packets.len(), 3);
+        assert!(matches!(replay.raw_packets[0][0], HQMObjectPacket::Puck(_)));
+        assert_eq!(replay.states[0].puck().unwrap().pos.x, 20.0);
+
+        // Without keep_raw_packets nothing is kept
+        let replay = parse_replay(&data).unwrap();
+        assert!(replay.raw_packets.is_empty());
+        assert_eq!(replay.states[0].puck().unwrap().pos.x, 10.0);
+    }
+
+    // A replay with a goal behind 13 extra bytes before the usual header
+    fn replay_with_extra_header() -> Vec<u8> {
+        let mut packets = packets(4);
+        packets[2].messages = vec![goal(HQMTeam::Red, None, None), chat(None, "hi")];
+        packets[2].red_score = 1;
+        packets[3].msg_pos = 2;
+        packets[3].red_score = 1;
+        let mut data = vec![0xAA; 13];
+        data.extend(replay_bytes(&packets));
+        data
+    }
+
+    #[test]
+    fn streaming_parser_detects_the_header() {
+        let data = replay_with_extra_header();
+        let options = ParserOptions::default().detect_header(true);
+        let expected = ReplayParser::with_options(&data, options.clone())
+            .parse()
+            .unwrap()
+            .states;
+        assert_eq!(expected.len(), 4);
+
+        // Fed a byte at a time, like a file that is still being recorded
+        let mut parser = StreamingParser::with_options(options);
+        let mut states = vec![];
+        for end in 0..=data.len() {
+            states.extend(parser.feed(&data[..end]).unwrap());
+        }
+        assert_eq!(states, expected);
+    }
+
+    #[test]
+    fn events_only_with_header_options() {
+        let data = replay_with_extra_header();
+        for options in [
+            ParserOptions::default().header_skip(21),
+            ParserOptions::default().detect_header(true),
+        ] {
+            let (goals, chat) = parse_events_only_with(&data, &options).unwrap();
+            assert_eq!(goals.len(), 1);
+            assert_eq!((goals[0].tick, goals[0].red_score), (2, 1));
+            assert_eq!(chat.len(), 1);
+            assert_eq!((chat[0].tick, chat[0].message.as_str()), (2, "hi"));
+        }
+    }
+
+    #[test]
+    fn packets_ending_on_and_off_a_byte_boundary() {
+        // Without messages a packet is 25 bytes of whole bytes and one unused byte. A goal
+        // message adds 20 bits, so the packet ends in a partly used byte.
+        let mut packets = packets(4);
+        packets[1].messages = vec![goal(HQMTeam::Red, None, None)];
+        packets[1].red_score = 1;
+        packets[2].msg_pos = 1;
+        packets[2].red_score = 1;
+        packets[3].msg_pos = 1;
+        packets[3].red_score = 1;
+        let data = replay_bytes(&packets);
+        assert_eq!(data.len(), 8 + 26 + 28 + 26 + 26);
+
+        let replay = parse_replay(&data).unwrap();
+        assert_eq!(replay.states.len(), 4);
+        assert!(replay.warnings.is_empty());
+        let times: Vec<_> = replay.states.iter().map(|x| x.time).collect();
+        assert_eq!(times, vec![30000, 29999, 29998, 29997]);
+        assert_eq!(goal_events(&replay.states).len(), 1);
+    }
+
+    #[test]
+    fn inputs_smaller_than_the_header_are_too_small() {
+        for size in [0, 3, 7] {
+            let data = vec![0; size];
+            let err = parse_replay(&data).unwrap_err();
+            assert_eq!(err, HQMParseError::TooSmall { size });
+            assert_eq!(parse_header(&data).unwrap_err(), err);
+        }
+        // Just the header is an empty replay
+        assert!(parse_replay(&[0; 8]).unwrap().states.is_empty());
+    }
+
+    #[test]
+    fn zero_length_field_is_a_warning() {
+        let mut packets = packets(3);
+        packets[1].messages = vec![goal(HQMTeam::Blue, None, None)];
+        packets[1].blue_score = 1;
+        packets[2].msg_pos = 1;
+        packets[2].blue_score = 1;
+        let correct = replay_bytes(&packets);
+        assert!(parse_replay(&correct).unwrap().warnings.is_empty());
+        let mut data = correct.clone();
+        data[4..8].copy_from_slice(&[0; 4]);
+
+        let replay = parse_replay(&data).unwrap();
+        let available = data.len() - 8;
+        assert_eq!(
+            replay.warnings,
+            vec![ParseWarning::LengthMismatch {
+                declared: 0,
+                available
+            }]
+        );
+        assert_eq!(replay.states, parse_replay(&correct).unwrap().states);
+        assert_eq!(replay.states.len(), 3);
+        assert_eq!(goal_events(&replay.states).len(), 1);
+    }
+
+    #[test]
+    fn parse_file_keeps_warnings() {
+        let mut data = replay_bytes(&packets(2));
+        data[4..8].copy_from_slice(&[0; 4]);
+        let path = std::env::temp_dir().join(format!("parse-file-{}.hrp", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let replay = parse_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let replay = replay.unwrap();
+        assert_eq!(replay.states.len(), 2);
+        assert_eq!(
+            replay.warnings,
+            vec![ParseWarning::LengthMismatch {
+                declared: 0,
+                available: data.len() - 8
+            }]
+        );
+    }
+
+    #[test]
+    fn concat_continues_numbering() {
+        let mut first = packets(2);
+        first[1].messages = vec![goal(HQMTeam::Red, None, None)];
+        first[1].red_score = 1;
+        let mut second = packets(2);
+        for packet in second.iter_mut() {
+            packet.packet_number += 50;
+            packet.red_score = 1;
+        }
+        second[1].msg_pos = 1;
+        second[1].messages = vec![chat(None, "back")];
+        let (a, b) = (replay_bytes(&first), replay_bytes(&second));
+
+        let states = concat_replays(&a, &b).unwrap();
+        let numbers: Vec<_> = states.iter().map(|x| x.packet_number).collect();
+        assert_eq!(numbers, vec![0, 1, 2, 3]);
+        assert_eq!(states[3].msg_pos, 2);
+        assert_eq!(goal_events(&states).len(), 1);
+        assert_eq!(chat_lines(&states)[0].tick, 3);
+
+        let joined = join_replays(parse_replay(&a).unwrap(), parse_replay(&b).unwrap());
+        assert_eq!(joined.states, states);
+        assert!(joined.warnings.is_empty());
+    }
+
+    #[test]
+    fn join_warns_when_the_score_goes_back() {
+        let mut first = packets(2);
+        first[1].red_score = 1;
+        let a = parse_replay(&replay_bytes(&first)).unwrap();
+        let b = parse_replay(&replay_bytes(&packets(2))).unwrap();
+        assert_eq!(
+            join_replays(a, b).warnings,
+            vec![ParseWarning::SplitMismatch {
+                tick: 2,
+                from: (1, 1, 0),
+                to: (1, 0, 0)
+            }]
+        );
+    }
+
+    #[test]
+    fn concat_tells_which_part_failed() {
+        let a = replay_bytes(&packets(2));
+        assert_eq!(
+            concat_replays(&a, &[0; 3]).unwrap_err(),
+            HQMParseError::SplitPart {
+                part: 2,
+                error: Box::new(HQMParseError::TooSmall { size: 3 })
+            }
+        );
+        assert!(matches!(
+            concat_replays(&[], &a),
+            Err(HQMParseError::SplitPart { part: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn chat_longer_than_the_data_is_unexpected_eof() {
+        let mut w = BitWriter::default();
+        write_message(&mut w, &chat(None, "hello there"));
+        let bytes = &w.bytes[..w.bytes.len() - 5];
+        let mut reader = HQMMessageReader::new(bytes);
+        let options = ParserOptions::default();
+        assert_eq!(
+            read_message(&mut reader, &options).unwrap_err(),
+            HQMParseError::UnexpectedEof
+        );
+
+        // The same cut at the end of a replay
+        let mut packets = packets(3);
+        packets[2].messages = vec![chat(None, "hello there")];
+        let mut data = replay_bytes(&packets);
+        data.truncate(data.len() - 5);
+        assert_eq!(
+            parse_replay(&data).unwrap_err(),
+            HQMParseError::UnexpectedEof
+        );
+        let options = ParserOptions::default().lenient(true);
+        let replay = ReplayParser::with_options(&data, options).parse().unwrap();
+        assert_eq!(replay.states.len(), 2);
+    }
+
+    #[test]
+    fn padding_is_ignored_with_a_warning() {
+        let mut packets = packets(3);
+        packets[1].messages = vec![goal(HQMTeam::Red, None, None)];
+        packets[1].red_score = 1;
+        packets[2].msg_pos = 1;
+        packets[2].red_score = 1;
+        let data = replay_bytes(&packets);
+        let expected = parse_replay(&data).unwrap();
+        assert!(expected.warnings.is_empty());
+
+        for padding in [vec![0; 40], vec![5; 3]] {
+            let mut padded = data.clone();
+            padded.extend(&padding);
+            let replay = parse_replay(&padded).unwrap();
+            assert_eq!(replay.states, expected.states);
+            assert!(replay.warnings.contains(&ParseWarning::TrailingData {
+                offset: data.len(),
+                length: padding.len(),
+            }));
+            assert_eq!(parse_events_only(&padded).unwrap().0.len(), 1);
+        }
+    }
+
+    #[test]
+    fn goal_in_a_footer_is_not_counted() {
+        // The last packet without its marker, like a footer that happens to hold a goal
+        let mut packets = packets(3);
+        packets[2].messages = vec![goal(HQMTeam::Blue, None, None)];
+        packets[2].blue_score = 1;
+        let mut data = replay_bytes(&packets);
+        let footer = HEADER_SIZE + 2 * 26;
+        data[footer] = 0;
+
+        let replay = parse_replay(&data).unwrap();
+        assert_eq!(replay.states.len(), 2);
+        assert_eq!(
+            replay.warnings,
+            vec![ParseWarning::TrailingData {
+                offset: footer,
+                length: data.len() - footer,
+            }]
+        );
+        let (goals, _) = parse_events_only(&data).unwrap();
+        assert!(goals.is_empty());
+        assert_eq!(goals.len(), goal_events(&replay.states).len());
+    }
+
+    // A puck one unit from the edge, then moved three units past it by a delta
+    fn puck_below_zero() -> Vec<u8> {
+        let mut packets = packets(2);
+        let puck = |x: i32| {
+            HQMObjectPacket::Puck(HQMPuckPacket {
+                pos: (x as u32, 512, 20480),
+                rot: (0, 0),
+            })
+        };
+        packets[0].objects = vec![puck(1)];
+        packets[1].objects = vec![puck(-2)];
+        packets[1].reference = Some(0);
+        replay_bytes(&packets)
+    }
+
+    fn parse_with_clamp(data: &[u8], clamp: PositionClampPolicy) -> Result<Replay, HQMParseError> {
+        let options = ParserOptions::default().position_clamp(clamp);
+        ReplayParser::with_options(data, options).parse()
+    }
+
+    #[test]
+    fn clamp_to_zero_policy() {
+        let replay = parse_with_clamp(&puck_below_zero(), PositionClampPolicy::ClampToZero);
+        assert_eq!(replay.unwrap().states[1].puck().unwrap().pos.x, 0.0);
+    }
+
+    #[test]
+    fn allow_negative_policy() {
+        let replay = parse_with_clamp(&puck_below_zero(), PositionClampPolicy::AllowNegative);
+        let states = replay.unwrap().states;
+        assert_eq!(states[0].puck().unwrap().pos.x, 1.0 / 1024.0);
+        assert_eq!(states[1].puck().unwrap().pos.x, -2.0 / 1024.0);
+    }
+
+    #[test]
+    fn error_policy() {
+        let err = parse_with_clamp(&puck_below_zero(), PositionClampPolicy::Error).unwrap_err();
+        assert!(matches!(
+            err,
+            HQMParseError::NegativePosition { value: -2, .. }
+        ));
+    }
+
+    #[test]
+    fn negative_body_values_stay_negative() {
+        let packet = HQMObjectPacket::Skater(HQMSkaterPacket {
+            pos: (0, 0, 0),
+            rot: (0, 0),
+            stick_pos: (4096, 4096, 4096),
+            stick_rot: (0, 0),
+            body_turn: -8192i32 as u32,
+            body_lean: 8192,
+        });
+        let objects = convert_object_packets(&[packet], &ParserOptions::default());
+        let HQMGameObject::Player(skater) = &objects[0] else {
+            panic!("expected a skater");
+        };
+        assert_eq!(skater.body_turn, -3.0);
+        assert_eq!(skater.body_lean, -1.0);
+    }
+
+    #[test]
+    fn strict_fails_where_default_stops() {
+        let mut data = replay_bytes(&packets(2));
+        data.extend([7; 40]);
+
+        let replay = parse_replay(&data).unwrap();
+        assert_eq!(replay.states.len(), 2);
+
+        let options = ParserOptions::default().strict(true);
+        let err = ReplayParser::with_options(&data, options)
+            .parse()
+            .unwrap_err();
+        let pos = data.len() - 40;
+        assert_eq!(err, HQMParseError::InvalidPacketMarker { pos, marker: 7 });
+    }
+
+    #[test]
+    fn lenient_drops_cut_off_packet() {
+        // Only the last byte of the last packet is missing
+        let mut data = replay_bytes(&packets(3));
+        data.truncate(data.len() - 1);
+
+        assert_eq!(parse_replay(&data).unwrap().states.len(), 3);
+
+        let options = ParserOptions::default().lenient(true);
+        let replay = ReplayParser::with_options(&data, options).parse().unwrap();
+        assert_eq!(replay.states.len(), 2);
+
+        let options = ParserOptions::default().strict(true);
+        let err = ReplayParser::with_options(&data, options)
+            .parse()
+            .unwrap_err();
+        assert_eq!(err, HQMParseError::UnexpectedEof);
+    }
+
+    #[test]
+    fn repeated_unknown_message_ends_the_packet() {
+        let unknown = HQMMessage::Unknown {
+            message_type: 9,
+            raw_bits: vec![0x2A, 0x11],
+        };
+        let mut packets = packets(3);
+        packets[0].messages = vec![unknown.clone()];
+        // The unknown message is repeated, and the chat after it is swallowed with it
+        packets[1].messages = vec![unknown, chat(None, "lost")];
+        packets[2].msg_pos = 2;
+        packets[2].messages = vec![chat(None, "hello")];
+        let data = replay_bytes(&packets);
+
+        let options = ParserOptions::default().lenient(true);
+        let replay = ReplayParser::with_options(&data, options).parse().unwrap();
+        assert_eq!(replay.states.len(), 3);
+        assert!(matches!(
+            replay.states[0].messages_in_this_packet[..],
+            [HQMMessage::Unknown {
+                message_type: 9,
+                ..
+            }]
+        ));
+        assert!(replay.states[1].messages_in_this_packet.is_empty());
+        assert_eq!(
+            replay.states[2].messages_in_this_packet,
+            vec![chat(None, "hello")]
+        );
+        assert_eq!(replay.states[2].time, 29998);
+    }
+
+    #[test]
+    fn overlapping_message_windows_give_each_message_once() {
+        let mut packets = packets(4);
+        packets[0].messages = vec![join(0, "alice", Some((1, HQMTeam::Red)))];
+        packets[1].red_score = 1;
+        packets[1].messages = vec![
+            join(0, "alice", Some((1, HQMTeam::Red))),
+            goal(HQMTeam::Red, Some(0), None),
+        ];
+        packets[2].red_score = 1;
+        packets[2].msg_pos = 1;
+        packets[2].messages = vec![goal(HQMTeam::Red, Some(0), None), chat(Some(0), "gg")];
+        packets[3].red_score = 1;
+        packets[3].msg_pos = 1;
+        packets[3].messages = vec![goal(HQMTeam::Red, Some(0), None), chat(Some(0), "gg")];
+        let data = replay_bytes(&packets);
+
+        let replay = parse_replay(&data).unwrap();
+        let messages: Vec<_> = replay
+            .states
+            .iter()
+            .map(|x| x.messages_in_this_packet.clone())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                vec![join(0, "alice", Some((1, HQMTeam::Red)))],
+                vec![goal(HQMTeam::Red, Some(0), None)],
+                vec![chat(Some(0), "gg")],
+                vec![],
+            ]
+        );
+
+        let goals = goal_events(&replay.states);
+        assert_eq!(goals.len(), 1);
+        assert_eq!(goals[0].tick, 1);
+        assert_eq!(goals[0].goal_player_name.as_deref(), Some("alice"));
+        let chat = chat_lines(&replay.states);
+        assert_eq!(chat.len(), 1);
+        assert_eq!(chat[0].tick, 2);
+
+        let (goals, chat) = parse_events_only(&data).unwrap();
+        assert_eq!(goals.len(), 1);
+        assert_eq!(goals[0].tick, 1);
+        assert_eq!(chat.len(), 1);
+        assert_eq!(chat[0].tick, 2);
+    }
+
+    #[test]
+    fn message_position_reset_recovers() {
+        let mut packets = packets(3);
+        packets[0].msg_pos = 500;
+        packets[0].messages = vec![chat(None, "before")];
+        // The server starts counting from 0 again
+        packets[1].messages = vec![chat(None, "lost")];
+        packets[2].msg_pos = 1;
+        packets[2].messages = vec![chat(None, "after")];
+        let data = replay_bytes(&packets);
+
+        let replay = parse_replay(&data).unwrap();
+        let chat: Vec<_> = chat_lines(&replay.states)
+            .into_iter()
+            .map(|x| x.message)
+            .collect();
+        assert_eq!(chat, vec!["before", "after"]);
+    }
+
+    #[test]
+    fn collapse_goal_repeats() {
+        let mut packets = packets(3);
+        packets[1].red_score = 1;
+        packets[1].messages = vec![goal(HQMTeam::Red, None, None)];
+        packets[2].red_score = 1;
+        packets[2].msg_pos = 1;
+        packets[2].messages = vec![goal(HQMTeam::Red, None, None)];
+        let data = replay_bytes(&packets);
+
+        let count = |options: ParserOptions| {
+            let replay = ReplayParser::with_options(&data, options).parse().unwrap();
+            replay
+                .states
+                .iter()
+                .map(|x| x.messages_in_this_packet.len())
+                .sum::<usize>()
+        };
+        assert_eq!(count(ParserOptions::default()), 2);
+        assert_eq!(
+            count(ParserOptions::default().collapse_goal_repeats(true)),
+            1
+        );
+        assert_eq!(
+            count(
+                ParserOptions::default()
+                    .collapse_goal_repeats(true)
+                    .strict(true)
+            ),
+            1
+        );
+    }
+}
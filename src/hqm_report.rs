@@ -0,0 +1,389 @@
+use crate::hqm_detect::{
+    run_detectors, EventDetector, FaceoffDetector, FaceoffResult, ShotDetector,
+};
+use crate::hqm_events::{goal_events_with, player_name, GoalEvent};
+use crate::hqm_game::{HQMGameState, HQMTeam, DEFAULT_TICK_RATE};
+use crate::hqm_rink::RinkGeometry;
+use crate::hqm_stats::{faceoff_win_percentages, puck_possession, DEFAULT_POSSESSION_THRESHOLD};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PlayerStats {
+    pub player_index: usize,
+    pub name: String,
+    // Team of the last skater the player had
+    pub team: Option<HQMTeam>,
+    // Seconds with a skater on the ice
+    pub time_on_ice: f32,
+    // Meters skated, horizontally
+    pub distance: f32,
+    // Meters per second
+    pub top_speed: f32,
+    // Seconds with the puck
+    pub possession: f32,
+    pub shots: u32,
+    pub goals: u32,
+    pub assists: u32,
+}
+
+/// Seconds the puck spent in each zone. The zones are named after the team defending them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ZoneTime {
+    pub red_zone: f32,
+    pub neutral_zone: f32,
+    pub blue_zone: f32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FaceoffStats {
+    pub total: u32,
+    pub red_wins: u32,
+    pub blue_wins: u32,
+    // Of the faceoffs someone won, see `faceoff_win_percentages`
+    pub red_percentage: f32,
+    pub blue_percentage: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsReport {
+    pub red_score: u32,
+    pub blue_score: u32,
+    pub players: Vec<PlayerStats>,
+    pub zone_time: ZoneTime,
+    pub faceoffs: FaceoffStats,
+}
+
+// Scorers listed in a box score
+const BOX_SCORE_SCORERS: usize = 5;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TeamBoxScore {
+    pub goals: u32,
+    pub shots: u32,
+    pub faceoff_wins: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScorerLine {
+    pub player_index: usize,
+    pub name: String,
+    pub team: HQMTeam,
+    pub goals: u32,
+    pub assists: u32,
+}
+
+impl ScorerLine {
+    pub fn points(&self) -> u32 {
+        self.goals + self.assists
+    }
+}
+
+/// The short summary of a game: team totals and the players with the most points.
+#[derive(Debug, Clone, Serialize)]
+pub struct BoxScore {
+    pub red: TeamBoxScore,
+    pub blue: TeamBoxScore,
+    // Most points first, then most goals
+    pub top_scorers: Vec<ScorerLine>,
+}
+
+impl BoxScore {
+    pub fn team(&self, team: HQMTeam) -> &TeamBoxScore {
+        match team {
+            HQMTeam::Red => &self.red,
+            HQMTeam::Blue => &self.blue,
+        }
+    }
+
+    fn team_mut(&mut self, team: HQMTeam) -> &mut TeamBoxScore {
+        match team {
+            HQMTeam::Red => &mut self.red,
+            HQMTeam::Blue => &mut self.blue,
+        }
+    }
+}
+
+/// Accumulates per-player stats from skater movement and possession.
+/// Shots, goals and assists are filled in by `stats_report`.
+#[derive(Default)]
+pub struct PlayerStatsCollector {
+    players: BTreeMap<usize, PlayerStats>,
+}
+
+impl EventDetector for PlayerStatsCollector {
+    type Event = PlayerStats;
+
+    fn observe(&mut self, prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        let tick_time = 1.0 / DEFAULT_TICK_RATE;
+        for (player_index, team, skater) in cur.skaters() {
+            let stats = self
+                .players
+                .entry(player_index)
+                .or_insert_with(|| PlayerStats {
+                    player_index,
+                    ..Default::default()
+                });
+            stats.name = player_name(cur, player_index).unwrap_or_default();
+            stats.team = Some(team);
+            stats.time_on_ice += tick_time;
+            let prev_pos = prev.and_then(|prev| {
+                prev.skaters()
+                    .find(|(i, _, _)| *i == player_index)
+                    .map(|(_, _, skater)| skater.pos)
+            });
+            if let Some(prev_pos) = prev_pos {
+                let delta = skater.pos - prev_pos;
+                let moved = (delta.x * delta.x + delta.z * delta.z).sqrt();
+                stats.distance += moved;
+                stats.top_speed = stats.top_speed.max(moved * DEFAULT_TICK_RATE);
+            }
+        }
+        if let Some((player_index, _)) = puck_possession(cur, DEFAULT_POSSESSION_THRESHOLD) {
+            if let Some(stats) = self.players.get_mut(&player_index) {
+                stats.possession += tick_time;
+            }
+        }
+    }
+
+    fn finish(self) -> Vec<PlayerStats> {
+        self.players.into_values().collect()
+    }
+}
+
+/// Puck time per zone, split at the blue lines.
+pub struct ZoneTimeCollector {
+    rink: RinkGeometry,
+    zone_time: ZoneTime,
+}
+
+impl ZoneTimeCollector {
+    pub fn new(rink: RinkGeometry) -> Self {
+        ZoneTimeCollector {
+            rink,
+            zone_time: ZoneTime::default(),
+        }
+    }
+}
+
+impl EventDetector for ZoneTimeCollector {
+    type Event = ZoneTime;
+
+    fn observe(&mut self, _prev: Option<&HQMGameState>, cur: &HQMGameState) {
+        let tick_time = 1.0 / DEFAULT_TICK_RATE;
+        if let Some(puck) = cur.puck() {
+            if self.rink.in_defending_zone(HQMTeam::Red, &puck.pos) {
+                self.zone_time.red_zone += tick_time;
+            } else if self.rink.in_defending_zone(HQMTeam::Blue, &puck.pos) {
+                self.zone_time.blue_zone += tick_time;
+            } else {
+                self.zone_time.neutral_zone += tick_time;
+            }
+        }
+    }
+
+    fn finish(self) -> Vec<ZoneTime> {
+        vec![self.zone_time]
+    }
+}
+
+/// Everything in one report. Movement, possession, shots, zone time and faceoffs are collected
+/// in a single pass over the states.
+pub fn stats_report(states: &[HQMGameState], rink: &RinkGeometry) -> StatsReport {
+    let mut players = PlayerStatsCollector::default();
+    let mut shots = ShotDetector::new(rink.clone());
+    let mut zones = ZoneTimeCollector::new(rink.clone());
+    let mut faceoffs = FaceoffDetector::new(rink.clone());
+    run_detectors(
+        states,
+        &mut [&mut players, &mut shots, &mut zones, &mut faceoffs],
+    );
+
+    let mut players = players.finish();
+    for shot in shots.finish() {
+        if let Some(stats) = find_player(&mut players, shot.player) {
+            stats.shots += 1;
+        }
+    }
+    for goal in goal_events_with(states, rink) {
+        if let Some(stats) = goal
+            .goal_player_index
+            .and_then(|x| find_player(&mut players, x))
+        {
+            stats.goals += 1;
+        }
+        if let Some(stats) = goal
+            .assist_player_index
+            .and_then(|x| find_player(&mut players, x))
+        {
+            stats.assists += 1;
+        }
+    }
+
+    let last = states.last();
+    StatsReport {
+        red_score: last.map_or(0, |x| x.red_score),
+        blue_score: last.map_or(0, |x| x.blue_score),
+        players,
+        zone_time: zones.finish().pop().unwrap_or_default(),
+        faceoffs: faceoff_stats(&faceoffs.finish()),
+    }
+}
+
+/// Goals, shots and faceoff wins per team, and the top scorers, in a single pass.
+pub fn box_score(frames: &[HQMGameState], rink: &RinkGeometry) -> BoxScore {
+    let mut shots = ShotDetector::new(rink.clone());
+    let mut faceoffs = FaceoffDetector::new(rink.clone());
+    run_detectors(frames, &mut [&mut shots, &mut faceoffs]);
+
+    let goals = goal_events_with(frames, rink);
+    let mut res = BoxScore {
+        red: TeamBoxScore::default(),
+        blue: TeamBoxScore::default(),
+        top_scorers: top_scorers(&goals),
+    };
+    for shot in shots.finish() {
+        res.team_mut(shot.team).shots += 1;
+    }
+    for faceoff in faceoffs.finish() {
+        if let Some(winner) = faceoff.winner {
+            res.team_mut(winner).faceoff_wins += 1;
+        }
+    }
+    for goal in goals.iter() {
+        res.team_mut(goal.team).goals += 1;
+    }
+    res
+}
+
+fn top_scorers(goals: &[GoalEvent]) -> Vec<ScorerLine> {
+    let mut scorers: BTreeMap<usize, ScorerLine> = BTreeMap::new();
+    for goal in goals {
+        let credits = [
+            (goal.goal_player_index, &goal.goal_player_name, true),
+            (goal.assist_player_index, &goal.assist_player_name, false),
+        ];
+        for (index, name, is_goal) in credits {
+            if let Some(player_index) = index {
+                let line = scorers.entry(player_index).or_insert_with(|| ScorerLine {
+                    player_index,
+                    name: name.clone().unwrap_or_default(),
+                    team: goal.team,
+                    goals: 0,
+                    assists: 0,
+                });
+                if is_goal {
+                    line.goals += 1;
+                } else {
+                    line.assists += 1;
+                }
+            }
+        }
+    }
+    let mut res: Vec<_> = scorers.into_values().collect();
+    res.sort_by(|a, b| b.points().cmp(&a.points()).then(b.goals.cmp(&a.goals)));
+    res.truncate(BOX_SCORE_SCORERS);
+    res
+}
+
+fn faceoff_stats(results: &[FaceoffResult]) -> FaceoffStats {
+    let wins = |team| results.iter().filter(|x| x.winner == Some(team)).count() as u32;
+    let (red_percentage, blue_percentage) = faceoff_win_percentages(results);
+    FaceoffStats {
+        total: results.len() as u32,
+        red_wins: wins(HQMTeam::Red),
+        blue_wins: wins(HQMTeam::Blue),
+        red_percentage,
+        blue_percentage,
+    }
+}
+
+fn find_player(players: &mut [PlayerStats], player_index: usize) -> Option<&mut PlayerStats> {
+    players.iter_mut().find(|x| x.player_index == player_index)
+}
+
+impl fmt::Display for StatsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Final score: Red {} - {} Blue",
+            self.red_score, self.blue_score
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<20} {:<5} {:>7} {:>8} {:>6} {:>6} {:>5} {:>5} {:>5}",
+            "Player", "Team", "TOI", "Dist", "Top", "Poss", "Shots", "G", "A"
+        )?;
+        for p in self.players.iter() {
+            let team = p.team.map(|x| format!("{:?}", x)).unwrap_or_default();
+            writeln!(
+                f,
+                "{:<20} {:<5} {:>6.0}s {:>7.0}m {:>6.1} {:>5.0}s {:>5} {:>5} {:>5}",
+                p.name,
+                team,
+                p.time_on_ice,
+                p.distance,
+                p.top_speed,
+                p.possession,
+                p.shots,
+                p.goals,
+                p.assists
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Puck zone time: red zone {:.0}s, neutral {:.0}s, blue zone {:.0}s",
+            self.zone_time.red_zone, self.zone_time.neutral_zone, self.zone_time.blue_zone
+        )?;
+        writeln!(
+            f,
+            "Faceoffs: {}, red won {} ({:.0}%), blue won {} ({:.0}%)",
+            self.faceoffs.total,
+            self.faceoffs.red_wins,
+            self.faceoffs.red_percentage,
+            self.faceoffs.blue_wins,
+            self.faceoffs.blue_percentage
+        )
+    }
+}
+
+impl fmt::Display for BoxScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<6} {:>5} {:>5} {:>8}",
+            "Team", "Goals", "Shots", "Faceoffs"
+        )?;
+        for (team, line) in [("Red", &self.red), ("Blue", &self.blue)] {
+            writeln!(
+                f,
+                "{:<6} {:>5} {:>5} {:>8}",
+                team, line.goals, line.shots, line.faceoff_wins
+            )?;
+        }
+        if self.top_scorers.is_empty() {
+            return Ok(());
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<20} {:<5} {:>3} {:>3} {:>3}",
+            "Player", "Team", "G", "A", "P"
+        )?;
+        for p in self.top_scorers.iter() {
+            writeln!(
+                f,
+                "{:<20} {:<5} {:>3} {:>3} {:>3}",
+                p.name,
+                format!("{:?}", p.team),
+                p.goals,
+                p.assists,
+                p.points()
+            )?;
+        }
+        Ok(())
+    }
+}
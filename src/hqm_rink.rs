@@ -0,0 +1,139 @@
+//! Rink dimensions used by the analyses.
+//!
+//! Coordinates are in meters with the origin in a corner of the rink at ice level.
+//! x runs across the rink from 0 to `width`, y points up and z runs along the rink from 0 to
+//! `length`. Red defends the net at high z, blue the one at low z. Positions decoded from a
+//! replay use the same system, so they can be compared with these measurements directly.
+
+use crate::hqm_game::HQMTeam;
+use nalgebra::Point3;
+
+#[derive(Debug, Clone)]
+pub struct RinkGeometry {
+    pub width: f32,
+    pub length: f32,
+    pub corner_radius: f32,
+    // Distance from the end boards to each goal line
+    pub goal_line_distance: f32,
+    // Distance from the end boards to the neutral zone edge of each blue line
+    pub blue_line_distance: f32,
+    pub line_width: f32,
+    // Distance between the goal posts
+    pub net_width: f32,
+    pub crease_radius: f32,
+    // The slot extends this far out from the goal line, and this wide centered on the net
+    pub slot_depth: f32,
+    pub slot_width: f32,
+}
+
+impl Default for RinkGeometry {
+    fn default() -> Self {
+        Self::hqm_default()
+    }
+}
+
+impl RinkGeometry {
+    /// The rink used by the standard HQM server. Servers with modded rinks can start from
+    /// this and override individual fields.
+    pub fn hqm_default() -> Self {
+        RinkGeometry {
+            width: 30.0,
+            length: 61.0,
+            corner_radius: 8.5,
+            goal_line_distance: 4.0,
+            blue_line_distance: 22.86,
+            line_width: 0.3,
+            net_width: 3.0,
+            crease_radius: 1.8,
+            slot_depth: 9.0,
+            slot_width: 8.0,
+        }
+    }
+
+    /// z of the neutral zone edge of the blue line in front of the zone defended by `team`.
+    pub fn blue_line_z(&self, team: HQMTeam) -> f32 {
+        match team {
+            HQMTeam::Red => self.length - self.blue_line_distance,
+            HQMTeam::Blue => self.blue_line_distance,
+        }
+    }
+
+    /// z of the center of the goal line in front of the net defended by `team`.
+    pub fn goal_line_z(&self, team: HQMTeam) -> f32 {
+        match team {
+            HQMTeam::Red => self.length - self.goal_line_distance,
+            HQMTeam::Blue => self.goal_line_distance,
+        }
+    }
+
+    /// True if `pos` is past the goal line of the net defended by `team`, towards the end
+    /// boards. A puck that went in is behind the line.
+    pub fn behind_goal_line(&self, team: HQMTeam, pos: &Point3<f32>) -> bool {
+        match team {
+            HQMTeam::Red => pos.z > self.goal_line_z(team),
+            HQMTeam::Blue => pos.z < self.goal_line_z(team),
+        }
+    }
+
+    /// True if `pos` is past the blue line into the zone defended by `team`.
+    pub fn in_defending_zone(&self, team: HQMTeam, pos: &Point3<f32>) -> bool {
+        match team {
+            HQMTeam::Red => pos.z > self.blue_line_z(team),
+            HQMTeam::Blue => pos.z < self.blue_line_z(team),
+        }
+    }
+
+    /// Center of the goal mouth of the net defended by `team`.
+    /// Red defends the net at high z, blue the one at low z.
+    pub fn net_position(&self, team: HQMTeam) -> Point3<f32> {
+        Point3::new(self.width / 2.0, 0.0, self.goal_line_z(team))
+    }
+
+    pub fn in_crease(&self, team: HQMTeam, pos: &Point3<f32>) -> bool {
+        let net = self.net_position(team);
+        let dx = pos.x - net.x;
+        let dz = pos.z - net.z;
+        (dx * dx + dz * dz).sqrt() <= self.crease_radius
+    }
+
+    /// True if `pos` is in the slot or crease in front of the net defended by `team`.
+    pub fn in_scoring_area(&self, team: HQMTeam, pos: &Point3<f32>) -> bool {
+        let net = self.net_position(team);
+        let out = match team {
+            HQMTeam::Red => net.z - pos.z,
+            HQMTeam::Blue => pos.z - net.z,
+        };
+        self.in_crease(team, pos)
+            || ((0.0..=self.slot_depth).contains(&out)
+                && (pos.x - net.x).abs() <= self.slot_width / 2.0)
+    }
+
+    /// Horizontal distance from a point to the boards, taking the rounded corners into account.
+    pub fn distance_to_boards(&self, pos: &Point3<f32>) -> f32 {
+        let r = self.corner_radius;
+        let corner_x = if pos.x < r {
+            Some(r)
+        } else if pos.x > self.width - r {
+            Some(self.width - r)
+        } else {
+            None
+        };
+        let corner_z = if pos.z < r {
+            Some(r)
+        } else if pos.z > self.length - r {
+            Some(self.length - r)
+        } else {
+            None
+        };
+        if let (Some(cx), Some(cz)) = (corner_x, corner_z) {
+            let dx = pos.x - cx;
+            let dz = pos.z - cz;
+            r - (dx * dx + dz * dz).sqrt()
+        } else {
+            pos.x
+                .min(self.width - pos.x)
+                .min(pos.z)
+                .min(self.length - pos.z)
+        }
+    }
+}
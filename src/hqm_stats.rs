@@ -0,0 +1,1282 @@
+use crate::hqm_detect::{
+    run_detectors, EventDetector, FaceoffDetector, FaceoffResult, ShotDetector,
+};
+use crate::hqm_events::{chat_lines, goal_events, goal_events_with, periods};
+use crate::hqm_game::{
+    frame_timestamp, HQMGameState, HQMPuck, HQMSkater, HQMTeam, DEFAULT_TICK_RATE,
+};
+use crate::hqm_rink::RinkGeometry;
+use nalgebra::{Point3, UnitQuaternion, Vector3};
+use std::collections::HashMap;
+use std::time::Duration;
+
+fn nearest_stick(
+    state: &HQMGameState,
+    puck: &HQMPuck,
+    threshold: f32,
+) -> Option<(usize, HQMTeam, f32)> {
+    state
+        .skaters()
+        .map(|(player_index, team, skater)| {
+            let dist = (skater.stick_pos - puck.pos).norm();
+            (player_index, team, dist)
+        })
+        .filter(|(_, _, dist)| *dist <= threshold)
+        .min_by(|a, b| a.2.total_cmp(&b.2))
+}
+
+/// The player whose stick is closest to any puck, if it is within `threshold` meters.
+pub fn puck_possession(state: &HQMGameState, threshold: f32) -> Option<(usize, HQMTeam)> {
+    state
+        .pucks()
+        .filter_map(|(_, puck)| nearest_stick(state, puck, threshold))
+        .min_by(|a, b| a.2.total_cmp(&b.2))
+        .map(|(player_index, team, _)| (player_index, team))
+}
+
+/// Possession of each puck separately, keyed by the puck's object slot.
+pub fn puck_possessions(
+    state: &HQMGameState,
+    threshold: f32,
+) -> Vec<(usize, Option<(usize, HQMTeam)>)> {
+    state
+        .pucks()
+        .map(|(slot, puck)| {
+            let owner = nearest_stick(state, puck, threshold)
+                .map(|(player_index, team, _)| (player_index, team));
+            (slot, owner)
+        })
+        .collect()
+}
+
+/// Counts ticks of (red, blue, neutral) possession.
+/// With several pucks, a tick counts for the team closest to any of them.
+pub fn possession_split(frames: &[HQMGameState], threshold: f32) -> (u32, u32, u32) {
+    let mut red = 0;
+    let mut blue = 0;
+    let mut neutral = 0;
+    for frame in frames {
+        match puck_possession(frame, threshold) {
+            Some((_, HQMTeam::Red)) => red += 1,
+            Some((_, HQMTeam::Blue)) => blue += 1,
+            None => neutral += 1,
+        }
+    }
+    (red, blue, neutral)
+}
+
+/// Converts a possession split into percentages of all ticks.
+pub fn possession_percentages(split: (u32, u32, u32)) -> (f32, f32, f32) {
+    let (red, blue, neutral) = split;
+    let total = red + blue + neutral;
+    if total == 0 {
+        return (0.0, 0.0, 0.0);
+    }
+    let total = total as f32;
+    (
+        100.0 * red as f32 / total,
+        100.0 * blue as f32 / total,
+        100.0 * neutral as f32 / total,
+    )
+}
+
+// How close to the boards the puck has to be to count as pinned against them
+const BOARD_MARGIN: f32 = 1.0;
+
+/// Frame ranges (inclusive) where the puck moves less than `motion_eps` meters per tick
+/// for at least `min_ticks` ticks while within a meter of the boards.
+/// Only the first puck is considered.
+pub fn detect_puck_frozen(
+    frames: &[HQMGameState],
+    rink: &RinkGeometry,
+    min_ticks: usize,
+    motion_eps: f32,
+) -> Vec<(usize, usize)> {
+    let mut res = vec![];
+    let mut start: Option<usize> = None;
+    for i in 0..frames.len() {
+        let frozen = i > 0
+            && match (frames[i - 1].puck(), frames[i].puck()) {
+                (Some(prev), Some(cur)) => {
+                    (cur.pos - prev.pos).norm() < motion_eps
+                        && rink.distance_to_boards(&cur.pos) <= BOARD_MARGIN
+                }
+                _ => false,
+            };
+        if frozen {
+            if start.is_none() {
+                start = Some(i - 1);
+            }
+        } else if let Some(s) = start.take() {
+            if i - s >= min_ticks {
+                res.push((s, i - 1));
+            }
+        }
+    }
+    if let Some(s) = start {
+        if frames.len() - s >= min_ticks {
+            res.push((s, frames.len() - 1));
+        }
+    }
+    res
+}
+
+// Key used in chat_stats for messages sent by the server. Matches the 0x3F "no player" index on the wire.
+pub const SERVER_CHAT_INDEX: usize = 63;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChatStats {
+    pub messages: u32,
+    pub words: u32,
+    pub first_tick: usize,
+    pub last_tick: usize,
+}
+
+/// Chat activity per player index. Server messages are keyed by `SERVER_CHAT_INDEX`,
+/// players who never chat are left out.
+pub fn chat_stats(states: &[HQMGameState]) -> HashMap<usize, ChatStats> {
+    let mut res: HashMap<usize, ChatStats> = HashMap::new();
+    for line in chat_lines(states) {
+        let key = line.player_index.unwrap_or(SERVER_CHAT_INDEX);
+        let stats = res.entry(key).or_insert_with(|| ChatStats {
+            first_tick: line.tick,
+            ..Default::default()
+        });
+        stats.messages += 1;
+        stats.words += line.message.split_whitespace().count() as u32;
+        stats.last_tick = line.tick;
+    }
+    res
+}
+
+// How far from its own net a skater can be and still be considered the goalie
+const GOALIE_ZONE_RADIUS: f32 = 4.0;
+// A goalie has to be out of position for this long to count as pulled
+const GOALIE_PULL_MIN_TICKS: usize = 300;
+// Only the last two minutes of a period are considered
+const GOALIE_PULL_LATE_TIME: u32 = 12000;
+
+/// The skater of `team` closest to its own net, if anyone is near it.
+pub fn infer_goalie(state: &HQMGameState, team: HQMTeam, rink: &RinkGeometry) -> Option<usize> {
+    let net = rink.net_position(team);
+    state
+        .skaters()
+        .filter(|(_, skater_team, _)| *skater_team == team)
+        .map(|(player_index, _, skater)| {
+            let dx = skater.pos.x - net.x;
+            let dz = skater.pos.z - net.z;
+            (player_index, (dx * dx + dz * dz).sqrt())
+        })
+        .filter(|(_, dist)| *dist <= GOALIE_ZONE_RADIUS)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(player_index, _)| player_index)
+}
+
+/// True if `team` has no skater near its own net, i.e. the net is empty.
+pub fn empty_net(frame: &HQMGameState, team: HQMTeam, rink: &RinkGeometry) -> bool {
+    infer_goalie(frame, team, rink).is_none()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoaliePull {
+    pub team: HQMTeam,
+    pub start_tick: usize,
+    pub end_tick: usize,
+    pub resulted_in_goal_for: bool,
+    pub goal_against: bool,
+}
+
+/// Spans late in a period where a trailing team has nobody near its own net
+/// for a few seconds, i.e. the goalie has been pulled for an extra attacker.
+///
+/// The goalie is whoever `infer_goalie` finds within 4 m of the net, so the crease being
+/// empty for a moment while the goalie plays the puck doesn't count. The net has to stay
+/// empty for 3 seconds in the last two minutes of a period, with the team behind on the
+/// scoreboard and still having skaters on the ice.
+pub fn goalie_pulls(states: &[HQMGameState], rink: &RinkGeometry) -> Vec<GoaliePull> {
+    let mut res = vec![];
+    for team in [HQMTeam::Red, HQMTeam::Blue] {
+        let pulled = |state: &HQMGameState| {
+            let (own, other) = match team {
+                HQMTeam::Red => (state.red_score, state.blue_score),
+                HQMTeam::Blue => (state.blue_score, state.red_score),
+            };
+            own < other
+                && state.time <= GOALIE_PULL_LATE_TIME
+                && state.skaters().any(|(_, t, _)| t == team)
+                && empty_net(state, team, rink)
+        };
+        let mut start = None;
+        for i in 0..=states.len() {
+            let is_pulled = i < states.len() && pulled(&states[i]);
+            if is_pulled {
+                start.get_or_insert(i);
+            } else if let Some(s) = start.take() {
+                if i - s >= GOALIE_PULL_MIN_TICKS {
+                    // A tying goal ends the pull on its own tick, so that tick counts too
+                    let goals = goal_events_with(&states[s..(i + 1).min(states.len())], rink);
+                    res.push(GoaliePull {
+                        team,
+                        start_tick: s,
+                        end_tick: i - 1,
+                        resulted_in_goal_for: goals.iter().any(|g| g.team == team),
+                        goal_against: goals.iter().any(|g| g.team != team),
+                    });
+                }
+            }
+        }
+    }
+    res.sort_by_key(|x| x.start_tick);
+    res
+}
+
+/// The frame each goalie pull starts at and the team that pulled its goalie.
+/// See `goalie_pulls` for the heuristic and for when the pulls end.
+pub fn goalie_pull_frames(frames: &[HQMGameState], rink: &RinkGeometry) -> Vec<(usize, HQMTeam)> {
+    goalie_pulls(frames, rink)
+        .into_iter()
+        .map(|x| (x.start_tick, x.team))
+        .collect()
+}
+
+// Stick to puck distance used by analyses that don't take a possession threshold
+pub const DEFAULT_POSSESSION_THRESHOLD: f32 = 1.0;
+
+/// Frame ranges (inclusive) where the puck is in the slot or crease
+/// while a player of the attacking team has it.
+pub fn scoring_chances(frames: &[HQMGameState], rink: &RinkGeometry) -> Vec<(usize, usize)> {
+    let is_chance = |frame: &HQMGameState| {
+        let puck = match frame.puck() {
+            Some(puck) => puck,
+            None => return false,
+        };
+        match puck_possession(frame, DEFAULT_POSSESSION_THRESHOLD) {
+            Some((_, HQMTeam::Red)) => rink.in_scoring_area(HQMTeam::Blue, &puck.pos),
+            Some((_, HQMTeam::Blue)) => rink.in_scoring_area(HQMTeam::Red, &puck.pos),
+            None => false,
+        }
+    };
+    let mut res = vec![];
+    let mut start = None;
+    for (i, frame) in frames.iter().enumerate() {
+        if is_chance(frame) {
+            start.get_or_insert(i);
+        } else if let Some(s) = start.take() {
+            res.push((s, i - 1));
+        }
+    }
+    if let Some(s) = start {
+        res.push((s, frames.len() - 1));
+    }
+    res
+}
+
+// Change in puck velocity, in meters per tick, that counts as the puck being played
+const TOUCH_VELOCITY_CHANGE: f32 = 0.005;
+
+#[derive(Debug, Clone)]
+pub struct Touch {
+    pub tick: usize,
+    pub player: usize,
+    pub puck_pos: Point3<f32>,
+}
+
+/// Ticks where a stick is within `touch_dist` meters of the puck and the puck's velocity
+/// changes right after. The closest stick gets the touch. Only the first puck is considered.
+pub fn touches(states: &[HQMGameState], touch_dist: f32) -> Vec<Touch> {
+    let mut res = vec![];
+    for i in 1..states.len().saturating_sub(1) {
+        let (prev, cur, next) = match (states[i - 1].puck(), states[i].puck(), states[i + 1].puck())
+        {
+            (Some(prev), Some(cur), Some(next)) => (prev, cur, next),
+            _ => continue,
+        };
+        let velocity_before = cur.pos - prev.pos;
+        let velocity_after = next.pos - cur.pos;
+        if (velocity_after - velocity_before).norm() <= TOUCH_VELOCITY_CHANGE {
+            continue;
+        }
+        if let Some((player, _, _)) = nearest_stick(&states[i], cur, touch_dist) {
+            res.push(Touch {
+                tick: i,
+                player,
+                puck_pos: cur.pos,
+            });
+        }
+    }
+    res
+}
+
+fn leader(frame: &HQMGameState) -> Option<HQMTeam> {
+    if frame.red_score > frame.blue_score {
+        Some(HQMTeam::Red)
+    } else if frame.blue_score > frame.red_score {
+        Some(HQMTeam::Blue)
+    } else {
+        None
+    }
+}
+
+/// Frames where a team takes the lead, either from a tie or from the other team.
+/// Going back to a tie is not a lead change.
+pub fn lead_changes(frames: &[HQMGameState]) -> Vec<(usize, HQMTeam)> {
+    let mut res = vec![];
+    let mut last_leader = None;
+    for (i, frame) in frames.iter().enumerate() {
+        let current = leader(frame);
+        if let Some(team) = current {
+            if last_leader != Some(team) {
+                res.push((i, team));
+            }
+        }
+        last_leader = current;
+    }
+    res
+}
+
+/// Red score minus blue score for every frame.
+pub fn score_differential(frames: &[HQMGameState]) -> Vec<i32> {
+    frames
+        .iter()
+        .map(|frame| frame.red_score as i32 - frame.blue_score as i32)
+        .collect()
+}
+
+/// Number of ticks `team` was ahead, including stoppages and intermissions.
+pub fn time_leading(frames: &[HQMGameState], team: HQMTeam) -> u32 {
+    frames
+        .iter()
+        .filter(|frame| leader(frame) == Some(team))
+        .count() as u32
+}
+
+/// The biggest goal difference at any point and the team that had it.
+/// The earliest one wins if both teams had the same largest lead, `None` if nobody ever led.
+pub fn largest_lead(frames: &[HQMGameState]) -> Option<(HQMTeam, u32)> {
+    let mut res: Option<(HQMTeam, u32)> = None;
+    for frame in frames {
+        if let Some(team) = leader(frame) {
+            let lead = frame.red_score.abs_diff(frame.blue_score);
+            if res.is_none_or(|(_, best)| lead > best) {
+                res = Some((team, lead));
+            }
+        }
+    }
+    res
+}
+
+/// (red, blue) skaters on the ice. Spectators are not counted.
+pub fn team_counts(state: &HQMGameState) -> (usize, usize) {
+    let mut red = 0;
+    let mut blue = 0;
+    for (_, team, _) in state.skaters() {
+        match team {
+            HQMTeam::Red => red += 1,
+            HQMTeam::Blue => blue += 1,
+        }
+    }
+    (red, blue)
+}
+
+/// (red skaters, blue skaters, spectators) on each tick. Spectators are connected players
+/// without a skater.
+pub fn player_count_timeline(states: &[HQMGameState]) -> Vec<(usize, usize, usize)> {
+    states
+        .iter()
+        .map(|state| {
+            let (red, blue) = team_counts(state);
+            let spectators = state
+                .player_list
+                .iter()
+                .flatten()
+                .filter(|player| player.team_and_skater.is_none())
+                .count();
+            (red, blue, spectators)
+        })
+        .collect()
+}
+
+/// Spin of the first puck in radians per second, as a rotation axis scaled by the angular
+/// speed. Computed from the rotation between each frame and the one before it, so the first
+/// frame and frames where either has no puck are `None`.
+pub fn puck_angular_velocity(frames: &[HQMGameState]) -> Vec<Option<Vector3<f32>>> {
+    let mut res = vec![None; frames.len().min(1)];
+    for pair in frames.windows(2) {
+        let spin = match (pair[0].puck(), pair[1].puck()) {
+            (Some(prev), Some(cur)) => {
+                let prev = UnitQuaternion::from_matrix(&prev.rot);
+                let cur = UnitQuaternion::from_matrix(&cur.rot);
+                let relative = cur * prev.inverse();
+                Some(relative.scaled_axis() * DEFAULT_TICK_RATE)
+            }
+            _ => None,
+        };
+        res.push(spin);
+    }
+    res
+}
+
+/// Speed in meters per second of the skater who has the puck, from the player's movement
+/// since the frame before. `None` when nobody is within `threshold` of the puck, and in the
+/// first frame or when the carrier only just appeared on the ice.
+pub fn carrier_speed(frames: &[HQMGameState], threshold: f32) -> Vec<Option<f32>> {
+    let mut res = vec![None; frames.len().min(1)];
+    for pair in frames.windows(2) {
+        let speed = puck_possession(&pair[1], threshold).and_then(|(player_index, _)| {
+            let position = |frame: &HQMGameState| {
+                frame
+                    .skaters()
+                    .find(|(i, _, _)| *i == player_index)
+                    .map(|(_, _, skater)| skater.pos)
+            };
+            let moved = position(&pair[1])? - position(&pair[0])?;
+            Some(moved.norm() * DEFAULT_TICK_RATE)
+        });
+        res.push(speed);
+    }
+    res
+}
+
+// Average carrier speed in meters per second for a carry to count as a rush
+const RUSH_SPEED: f32 = 7.0;
+// The puck can be away from the carrier's stick for this many ticks while stickhandling
+const RUSH_GAP_TICKS: usize = 30;
+
+// A carry in progress in detect_rushes
+struct Carry {
+    carrier: usize,
+    start_frame: usize,
+    start_zone: u32,
+    last_touch: usize,
+    speeds: Vec<f32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RushEvent {
+    pub carrier: usize,
+    pub start_frame: usize,
+    // Frame where the puck entered the attacking zone
+    pub end_frame: usize,
+    // 1 from the neutral zone, 2 from the carrier's own zone
+    pub zones_crossed: u32,
+}
+
+// 0 in the zone defended by `team`, 1 in the neutral zone and 2 in the attacking zone
+fn zone_index(rink: &RinkGeometry, team: HQMTeam, pos: &Point3<f32>) -> u32 {
+    let other = match team {
+        HQMTeam::Red => HQMTeam::Blue,
+        HQMTeam::Blue => HQMTeam::Red,
+    };
+    if rink.in_defending_zone(team, pos) {
+        0
+    } else if rink.in_defending_zone(other, pos) {
+        2
+    } else {
+        1
+    }
+}
+
+// A loose puck for longer than this isn't a turnover when the other team picks it up
+const TURNOVER_GAP_TICKS: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnoverEvent {
+    // Player who lost the puck
+    pub from: usize,
+    // Player of the other team who got it
+    pub to: usize,
+    pub frame: usize,
+    pub position: Point3<f32>,
+}
+
+/// The puck going from one team to the other inside the zone of the team that lost it.
+/// The pickup has to come within a second of the last touch, so a puck that was dumped
+/// in and later collected by the opponents doesn't count. Faceoffs after stoppages can
+/// show up here too when they are in a defensive zone.
+pub fn detect_turnovers(frames: &[HQMGameState], rink: &RinkGeometry) -> Vec<TurnoverEvent> {
+    let mut res = vec![];
+    // (player, team, last frame with the puck)
+    let mut last: Option<(usize, HQMTeam, usize)> = None;
+    for (i, frame) in frames.iter().enumerate() {
+        let (Some(puck), Some((player, team))) = (
+            frame.puck(),
+            puck_possession(frame, DEFAULT_POSSESSION_THRESHOLD),
+        ) else {
+            continue;
+        };
+        if let Some((from, from_team, at)) = last {
+            if from_team != team
+                && i - at <= TURNOVER_GAP_TICKS
+                && rink.in_defending_zone(from_team, &puck.pos)
+            {
+                res.push(TurnoverEvent {
+                    from,
+                    to: player,
+                    frame: i,
+                    position: puck.pos,
+                });
+            }
+        }
+        last = Some((player, team, i));
+    }
+    res
+}
+
+/// Ticks the puck spent in (defensive, neutral, offensive) zone as seen by `team`, i.e. the
+/// defensive zone is the one `team` defends. The zones are split at the blue lines. HQM
+/// teams don't switch ends between periods, so the whole game can be counted together.
+/// Frames without a puck aren't counted.
+pub fn puck_zone_time(
+    frames: &[HQMGameState],
+    rink: &RinkGeometry,
+    team: HQMTeam,
+) -> (u32, u32, u32) {
+    let mut res = (0, 0, 0);
+    for puck in frames.iter().filter_map(|frame| frame.puck()) {
+        match zone_index(rink, team, &puck.pos) {
+            0 => res.0 += 1,
+            1 => res.1 += 1,
+            _ => res.2 += 1,
+        }
+    }
+    res
+}
+
+/// Fast carries by one player that bring the puck into the attacking zone from further back.
+/// The carry starts when the player gets the puck and may lose it for a moment while
+/// stickhandling. It counts if the carrier averaged 7 m/s until the puck crossed the blue
+/// line. Passes end the carry, so passing rushes aren't found.
+pub fn detect_rushes(frames: &[HQMGameState], rink: &RinkGeometry) -> Vec<RushEvent> {
+    let speeds = carrier_speed(frames, DEFAULT_POSSESSION_THRESHOLD);
+    let mut res = vec![];
+    let mut carry: Option<Carry> = None;
+    for (i, frame) in frames.iter().enumerate() {
+        let Some(puck) = frame.puck() else {
+            carry = None;
+            continue;
+        };
+        let Some((player, team)) = puck_possession(frame, DEFAULT_POSSESSION_THRESHOLD) else {
+            if carry
+                .as_ref()
+                .is_some_and(|x| i - x.last_touch > RUSH_GAP_TICKS)
+            {
+                carry = None;
+            }
+            continue;
+        };
+        let zone = zone_index(rink, team, &puck.pos);
+        let current = match &mut carry {
+            Some(current) if current.carrier == player => current,
+            _ => carry.insert(Carry {
+                carrier: player,
+                start_frame: i,
+                start_zone: zone,
+                last_touch: i,
+                speeds: vec![],
+            }),
+        };
+        current.last_touch = i;
+        current.speeds.extend(speeds[i]);
+        if zone == 2 {
+            let average = current.speeds.iter().sum::<f32>() / current.speeds.len().max(1) as f32;
+            if current.start_zone < 2 && average >= RUSH_SPEED {
+                res.push(RushEvent {
+                    carrier: player,
+                    start_frame: current.start_frame,
+                    end_frame: i,
+                    zones_crossed: 2 - current.start_zone,
+                });
+            }
+            carry = None;
+        }
+    }
+    res
+}
+
+// Puck movement in meters per tick below which it counts as resting
+const PUCK_RESTING_SPEED: f32 = 0.01;
+
+/// Where the puck tends to sit still, as (cluster center, number of ticks), most common first.
+/// Resting ticks are grouped on a horizontal grid with cells `tolerance` meters wide and each
+/// cell's center is the mean position in it. Faceoff dots and the nets show up at the top,
+/// which makes this handy for checking `RinkGeometry` against real replays.
+pub fn common_puck_positions(states: &[HQMGameState], tolerance: f32) -> Vec<(Point3<f32>, u32)> {
+    let mut cells: HashMap<(i32, i32), (Vector3<f32>, u32)> = HashMap::new();
+    for pair in states.windows(2) {
+        let (prev, cur) = match (pair[0].puck(), pair[1].puck()) {
+            (Some(prev), Some(cur)) => (prev, cur),
+            _ => continue,
+        };
+        if (cur.pos - prev.pos).norm() >= PUCK_RESTING_SPEED {
+            continue;
+        }
+        let key = (
+            (cur.pos.x / tolerance).floor() as i32,
+            (cur.pos.z / tolerance).floor() as i32,
+        );
+        let cell = cells.entry(key).or_insert((Vector3::zeros(), 0));
+        cell.0 += cur.pos.coords;
+        cell.1 += 1;
+    }
+    let mut res: Vec<(Point3<f32>, u32)> = cells
+        .into_values()
+        .map(|(sum, count)| (Point3::from(sum / count as f32), count))
+        .collect();
+    res.sort_by_key(|x| std::cmp::Reverse(x.1));
+    res
+}
+
+/// (red, blue) goals scored in each period that had any.
+pub fn goals_per_period(frames: &[HQMGameState]) -> HashMap<u32, (u32, u32)> {
+    let mut res: HashMap<u32, (u32, u32)> = HashMap::new();
+    for goal in goal_events(frames) {
+        let entry = res.entry(goal.period).or_default();
+        match goal.team {
+            HQMTeam::Red => entry.0 += 1,
+            HQMTeam::Blue => entry.1 += 1,
+        }
+    }
+    res
+}
+
+/// (red, blue) shots in each period, as found by `ShotDetector`. Every period in the replay
+/// has an entry, also ones without shots, so a box score can print a column for each.
+pub fn shots_by_period(frames: &[HQMGameState], rink: &RinkGeometry) -> HashMap<u32, (u32, u32)> {
+    let mut res: HashMap<u32, (u32, u32)> = periods(frames)
+        .into_iter()
+        .map(|period| (period.number, (0, 0)))
+        .collect();
+    let mut detector = ShotDetector::new(rink.clone());
+    run_detectors(frames, &mut [&mut detector]);
+    for shot in detector.finish() {
+        let entry = res.entry(frames[shot.tick].period).or_default();
+        match shot.team {
+            HQMTeam::Red => entry.0 += 1,
+            HQMTeam::Blue => entry.1 += 1,
+        }
+    }
+    res
+}
+
+/// The period with the most goals, the earliest one on a tie. `None` if nobody scored.
+pub fn busiest_period(frames: &[HQMGameState]) -> Option<u32> {
+    goals_per_period(frames)
+        .into_iter()
+        .max_by(|a, b| {
+            let total = |x: &(u32, (u32, u32))| x.1 .0 + x.1 .1;
+            total(a).cmp(&total(b)).then(b.0.cmp(&a.0))
+        })
+        .map(|(period, _)| period)
+}
+
+/// (tick, player index) for every skater whose head is turned more than `threshold` radians
+/// away from where the body is facing, e.g. looking over the shoulder.
+/// `body_turn` is already measured relative to the body and runs from -2 to 2 radians,
+/// so only it is needed. `body_lean` is a sideways lean, not a heading, and is not used.
+pub fn looking_away(states: &[HQMGameState], threshold: f32) -> Vec<(usize, usize)> {
+    let mut res = vec![];
+    for (tick, state) in states.iter().enumerate() {
+        for (player_index, _, skater) in state.skaters() {
+            if skater.body_turn.abs() > threshold {
+                res.push((tick, player_index));
+            }
+        }
+    }
+    res
+}
+
+// A goal this many ticks or less after a shot by the same team is credited to the shot
+const SHOT_GOAL_TICKS: usize = 300;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShotMarker {
+    pub shooter: Option<usize>,
+    // Where the shot was taken, rotated so that every team attacks towards low z
+    pub pos: Point3<f32>,
+    // Meters per second
+    pub speed: f32,
+    pub goal: bool,
+}
+
+/// Shots for a shot location plot. Blue attacks towards high z, so blue shots are rotated
+/// half a turn around the center of the rink to line up with red's. Goals that the shot
+/// detector missed are included with the puck position and speed at the goal tick,
+/// and the scorer as shooter if there is one.
+pub fn shot_map(frames: &[HQMGameState], rink: &RinkGeometry) -> Vec<ShotMarker> {
+    let mut detector = ShotDetector::new(rink.clone());
+    run_detectors(frames, &mut [&mut detector]);
+    let shots = detector.finish();
+    let normalize = |team: HQMTeam, pos: Point3<f32>| match team {
+        HQMTeam::Red => pos,
+        HQMTeam::Blue => Point3::new(rink.width - pos.x, pos.y, rink.length - pos.z),
+    };
+
+    let mut res = vec![];
+    let mut matched = vec![false; shots.len()];
+    for goal in goal_events_with(frames, rink) {
+        // The last shot by the scoring team shortly before the goal
+        let shot = shots.iter().rposition(|shot| {
+            shot.team == goal.team
+                && shot.tick <= goal.tick
+                && goal.tick - shot.tick <= SHOT_GOAL_TICKS
+        });
+        match shot {
+            Some(i) => matched[i] = true,
+            None => {
+                let puck = match frames[goal.tick].puck() {
+                    Some(puck) => puck,
+                    None => continue,
+                };
+                let speed = goal
+                    .tick
+                    .checked_sub(1)
+                    .and_then(|prev| frames[prev].puck())
+                    .map_or(0.0, |prev| (puck.pos - prev.pos).norm());
+                res.push(ShotMarker {
+                    shooter: goal.goal_player_index,
+                    pos: normalize(goal.team, puck.pos),
+                    speed: speed * DEFAULT_TICK_RATE,
+                    goal: true,
+                });
+            }
+        }
+    }
+    for (shot, goal) in shots.into_iter().zip(matched) {
+        res.push(ShotMarker {
+            shooter: Some(shot.player),
+            pos: normalize(shot.team, shot.puck_pos),
+            speed: shot.speed * DEFAULT_TICK_RATE,
+            goal,
+        });
+    }
+    res
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrengthWindow {
+    pub start_tick: usize,
+    pub end_tick: usize,
+    pub red_skaters: usize,
+    pub blue_skaters: usize,
+}
+
+impl StrengthWindow {
+    pub fn is_even(&self) -> bool {
+        self.red_skaters == self.blue_skaters
+    }
+
+    /// "even" or red-on-blue like "4-on-3".
+    pub fn label(&self) -> String {
+        if self.is_even() {
+            "even".to_string()
+        } else {
+            format!("{}-on-{}", self.red_skaters, self.blue_skaters)
+        }
+    }
+}
+
+/// Spans (inclusive) with the same number of skaters on each team.
+pub fn strength_states(states: &[HQMGameState]) -> Vec<StrengthWindow> {
+    let mut res: Vec<StrengthWindow> = vec![];
+    for (tick, (red, blue, _)) in player_count_timeline(states).into_iter().enumerate() {
+        match res.last_mut() {
+            Some(window) if window.red_skaters == red && window.blue_skaters == blue => {
+                window.end_tick = tick
+            }
+            _ => res.push(StrengthWindow {
+                start_tick: tick,
+                end_tick: tick,
+                red_skaters: red,
+                blue_skaters: blue,
+            }),
+        }
+    }
+    res
+}
+
+/// Mean real time between consecutive goals, `None` with fewer than two goals.
+pub fn avg_time_between_goals(frames: &[HQMGameState], tick_rate: f32) -> Option<Duration> {
+    let goals = goal_events(frames);
+    if goals.len() < 2 {
+        return None;
+    }
+    let span = goals[goals.len() - 1].tick - goals[0].tick;
+    Some(frame_timestamp(span, tick_rate) / (goals.len() - 1) as u32)
+}
+
+/// The longest real time without a goal, counting from the start and up to the end of the
+/// replay as well as between goals. `None` for an empty replay.
+pub fn longest_scoreless_stretch(frames: &[HQMGameState], tick_rate: f32) -> Option<Duration> {
+    let last = frames.len().checked_sub(1)?;
+    let mut boundaries = vec![0];
+    boundaries.extend(goal_events(frames).iter().map(|goal| goal.tick));
+    boundaries.push(last);
+    let longest = boundaries.windows(2).map(|x| x[1] - x[0]).max()?;
+    Some(frame_timestamp(longest, tick_rate))
+}
+
+/// Frame ranges (inclusive) where the teams have different numbers of skaters, with red's
+/// skaters minus blue's, so positive when red has more.
+pub fn uneven_intervals(frames: &[HQMGameState]) -> Vec<(usize, usize, i32)> {
+    strength_states(frames)
+        .into_iter()
+        .filter(|window| !window.is_even())
+        .map(|window| {
+            let diff = window.red_skaters as i32 - window.blue_skaters as i32;
+            (window.start_tick, window.end_tick, diff)
+        })
+        .collect()
+}
+
+/// How `excitement` blends its three parts. Each part is scaled to 0..1 before weighting,
+/// so with weights summing to 1 the score is between 0 and 1 too.
+#[derive(Debug, Clone)]
+pub struct ExcitementWeights {
+    pub puck_speed: f32,
+    pub crowding: f32,
+    pub net_proximity: f32,
+    // Puck speed in meters per second that counts as fully exciting
+    pub max_speed: f32,
+    // Skaters within `crowd_radius` meters of the puck that count as fully exciting
+    pub max_crowd: usize,
+    pub crowd_radius: f32,
+    // Distance to a net in meters below which net proximity starts counting
+    pub net_range: f32,
+}
+
+impl Default for ExcitementWeights {
+    fn default() -> Self {
+        ExcitementWeights {
+            puck_speed: 0.4,
+            crowding: 0.3,
+            net_proximity: 0.3,
+            max_speed: 30.0,
+            max_crowd: 4,
+            crowd_radius: 3.0,
+            net_range: 15.0,
+        }
+    }
+}
+
+/// A per-tick score for picking highlights, using the default weights.
+pub fn excitement(states: &[HQMGameState]) -> Vec<(usize, f32)> {
+    excitement_with(
+        states,
+        &ExcitementWeights::default(),
+        &RinkGeometry::hqm_default(),
+    )
+}
+
+/// A per-tick score from how fast the puck moves, how many skaters are around it and how close
+/// it is to either net. Ticks without a puck score 0.
+pub fn excitement_with(
+    states: &[HQMGameState],
+    weights: &ExcitementWeights,
+    rink: &RinkGeometry,
+) -> Vec<(usize, f32)> {
+    let nets = [
+        rink.net_position(HQMTeam::Red),
+        rink.net_position(HQMTeam::Blue),
+    ];
+    states
+        .iter()
+        .enumerate()
+        .map(|(tick, state)| {
+            let puck = match state.puck() {
+                Some(puck) => puck,
+                None => return (tick, 0.0),
+            };
+            let speed = tick
+                .checked_sub(1)
+                .and_then(|prev| states[prev].puck())
+                .map_or(0.0, |prev| (puck.pos - prev.pos).norm() * DEFAULT_TICK_RATE);
+            let crowd = state
+                .skaters()
+                .filter(|(_, _, skater)| (skater.pos - puck.pos).norm() <= weights.crowd_radius)
+                .count();
+            let net_distance = nets
+                .iter()
+                .map(|net| (net - puck.pos).norm())
+                .fold(f32::INFINITY, f32::min);
+
+            let speed_part = (speed / weights.max_speed).min(1.0);
+            let crowd_part = (crowd as f32 / weights.max_crowd as f32).min(1.0);
+            let net_part = (1.0 - net_distance / weights.net_range).max(0.0);
+            let score = weights.puck_speed * speed_part
+                + weights.crowding * crowd_part
+                + weights.net_proximity * net_part;
+            (tick, score)
+        })
+        .collect()
+}
+
+/// Every faceoff in the game and which team won it.
+pub fn faceoff_results(frames: &[HQMGameState], rink: &RinkGeometry) -> Vec<FaceoffResult> {
+    let mut detector = FaceoffDetector::new(rink.clone());
+    run_detectors(frames, &mut [&mut detector]);
+    detector.finish()
+}
+
+// How far from center ice a faceoff can be and still count as the opening one
+const CENTER_FACEOFF_RADIUS: f32 = 2.0;
+
+/// The frame where the game really starts: the first faceoff at center ice in period 1,
+/// which is where the clock starts running after the warmup. Frames before it can be
+/// trimmed as pre-game footage. `None` if there is no such faceoff, e.g. when the
+/// recording starts later in the game.
+pub fn game_start_frame(frames: &[HQMGameState], rink: &RinkGeometry) -> Option<usize> {
+    let center = Point3::new(rink.width / 2.0, 0.0, rink.length / 2.0);
+    faceoff_results(frames, rink)
+        .into_iter()
+        .find(|faceoff| {
+            let dx = faceoff.location.x - center.x;
+            let dz = faceoff.location.z - center.z;
+            frames[faceoff.frame].period == 1 && (dx * dx + dz * dz).sqrt() <= CENTER_FACEOFF_RADIUS
+        })
+        .map(|faceoff| faceoff.frame)
+}
+
+/// Percentage of faceoffs won by (red, blue). Faceoffs nobody won are left out,
+/// so the two add up to 100 unless there are no decided faceoffs at all.
+pub fn faceoff_win_percentages(results: &[FaceoffResult]) -> (f32, f32) {
+    let red = results
+        .iter()
+        .filter(|x| x.winner == Some(HQMTeam::Red))
+        .count();
+    let blue = results
+        .iter()
+        .filter(|x| x.winner == Some(HQMTeam::Blue))
+        .count();
+    let total = red + blue;
+    if total == 0 {
+        return (0.0, 0.0);
+    }
+    (
+        red as f32 * 100.0 / total as f32,
+        blue as f32 * 100.0 / total as f32,
+    )
+}
+
+/// Which end of the rink play is at, for momentum graphs. Each frame's puck position along
+/// the rink is scaled to +1 at the blue end (low z, red attacking), -1 at the red end (high z,
+/// blue attacking) and 0 at center ice, then averaged over the last `window_ticks` frames.
+/// Frames without a puck are left out of the average, and a window with no puck gives 0.
+pub fn territory_control(
+    frames: &[HQMGameState],
+    rink: &RinkGeometry,
+    window_ticks: usize,
+) -> Vec<f32> {
+    let half = rink.length / 2.0;
+    let values: Vec<Option<f64>> = frames
+        .iter()
+        .map(|frame| {
+            frame
+                .puck()
+                .map(|puck| ((half - puck.pos.z) / half).clamp(-1.0, 1.0) as f64)
+        })
+        .collect();
+    let window = window_ticks.max(1);
+    let mut res = Vec::with_capacity(frames.len());
+    let (mut sum, mut count) = (0.0, 0);
+    for i in 0..values.len() {
+        if let Some(v) = values[i] {
+            sum += v;
+            count += 1;
+        }
+        if i >= window {
+            if let Some(v) = values[i - window] {
+                sum -= v;
+                count -= 1;
+            }
+        }
+        res.push(if count > 0 {
+            (sum / count as f64) as f32
+        } else {
+            0.0
+        });
+    }
+    res
+}
+
+/// Length in ticks of each continuous stretch every player spent on the ice, in order.
+/// A shift ends when the player's skater goes away, by spectating or leaving the server.
+/// A shift still going at the end of the replay is counted up to there.
+pub fn shift_lengths(frames: &[HQMGameState]) -> HashMap<usize, Vec<u32>> {
+    let mut res: HashMap<usize, Vec<u32>> = HashMap::new();
+    // Player index -> ticks on the ice so far in the current shift
+    let mut current: HashMap<usize, u32> = HashMap::new();
+    for frame in frames {
+        let on_ice: Vec<usize> = frame.skaters().map(|(i, _, _)| i).collect();
+        current.retain(|player_index, ticks| {
+            let stays = on_ice.contains(player_index);
+            if !stays {
+                res.entry(*player_index).or_default().push(*ticks);
+            }
+            stays
+        });
+        for player_index in on_ice {
+            *current.entry(player_index).or_default() += 1;
+        }
+    }
+    for (player_index, ticks) in current {
+        res.entry(player_index).or_default().push(ticks);
+    }
+    res
+}
+
+/// Average shift length in ticks for every player who was on the ice.
+pub fn average_shift_lengths(frames: &[HQMGameState]) -> HashMap<usize, f32> {
+    shift_lengths(frames)
+        .into_iter()
+        .map(|(player_index, shifts)| {
+            let total: u32 = shifts.iter().sum();
+            (player_index, total as f32 / shifts.len() as f32)
+        })
+        .collect()
+}
+
+/// The skater of the player called `name` in every frame, `None` while they are off the ice
+/// or not in the server. The player is looked up by name in each frame, so it doesn't matter
+/// if they reconnect with another player index or someone else gets their old one. Names
+/// are compared exactly. If several players share the name, the one followed in the frame
+/// before is kept, otherwise the one with the lowest index is taken.
+pub fn track_player(frames: &[HQMGameState], name: &str) -> Vec<Option<HQMSkater>> {
+    let mut followed: Option<usize> = None;
+    frames
+        .iter()
+        .map(|frame| {
+            let candidates: Vec<_> = frame
+                .skaters()
+                .filter(|(player_index, _, _)| {
+                    frame.player_list[*player_index]
+                        .as_ref()
+                        .is_some_and(|player| player.name == name)
+                })
+                .collect();
+            let (player_index, _, skater) = candidates
+                .iter()
+                .find(|(player_index, _, _)| Some(*player_index) == followed)
+                .or(candidates.first())?;
+            let player_index = *player_index;
+            followed = Some(player_index);
+            Some((*skater).clone())
+        })
+        .collect()
+}
+
+// A player who loses the puck for at most this many ticks and gets it back without anyone
+// else touching it has kept possession, e.g. while stickhandling
+const POSSESSION_GAP_TICKS: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PossessionStreak {
+    pub player: usize,
+    pub team: HQMTeam,
+    // Inclusive
+    pub start_frame: usize,
+    pub end_frame: usize,
+    pub ticks: u32,
+}
+
+/// Uninterrupted possessions by one player, in order. Short gaps where the puck is off the
+/// stick but nobody else gets it are bridged.
+pub fn possession_streaks(frames: &[HQMGameState], threshold: f32) -> Vec<PossessionStreak> {
+    let mut res: Vec<PossessionStreak> = vec![];
+    for (i, frame) in frames.iter().enumerate() {
+        let Some((player, team)) = puck_possession(frame, threshold) else {
+            continue;
+        };
+        match res.last_mut() {
+            Some(streak)
+                if streak.player == player && i - streak.end_frame <= POSSESSION_GAP_TICKS + 1 =>
+            {
+                streak.end_frame = i;
+                streak.ticks = (i - streak.start_frame + 1) as u32;
+            }
+            _ => {
+                res.push(PossessionStreak {
+                    player,
+                    team,
+                    start_frame: i,
+                    end_frame: i,
+                    ticks: 1,
+                });
+            }
+        }
+    }
+    res
+}
+
+/// (red, blue) longest possession streak by a single player of each team, the earliest one
+/// if several are equally long. Clip it with its frame range.
+pub fn longest_possession(
+    frames: &[HQMGameState],
+    threshold: f32,
+) -> (Option<PossessionStreak>, Option<PossessionStreak>) {
+    let streaks = possession_streaks(frames, threshold);
+    let longest = |team: HQMTeam| {
+        streaks
+            .iter()
+            .rev()
+            .filter(|streak| streak.team == team)
+            .max_by_key(|streak| streak.ticks)
+            .cloned()
+    };
+    (longest(HQMTeam::Red), longest(HQMTeam::Blue))
+}
+
+// Distances between every pair of skaters on the ice, in the order of `skaters()`
+fn skater_distances(frame: &HQMGameState) -> (Vec<Point3<f32>>, Vec<Vec<f32>>) {
+    let positions: Vec<_> = frame.skaters().map(|(_, _, skater)| skater.pos).collect();
+    let distances = positions
+        .iter()
+        .map(|a| positions.iter().map(|b| (a - b).norm()).collect())
+        .collect();
+    (positions, distances)
+}
+
+/// Frames where at least `min_players` skaters are bunched up, with the centroid of the group.
+/// The group is the skater with the most others within `radius` meters, together with those
+/// others, so in a frame with several groups only the biggest one is reported.
+pub fn detect_scrums(
+    frames: &[HQMGameState],
+    radius: f32,
+    min_players: usize,
+) -> Vec<(usize, Point3<f32>)> {
+    let mut res = vec![];
+    for (i, frame) in frames.iter().enumerate() {
+        let (positions, distances) = skater_distances(frame);
+        let group = distances
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .enumerate()
+                    .filter(|(_, &dist)| dist <= radius)
+                    .map(|(j, _)| j)
+                    .collect::<Vec<_>>()
+            })
+            .max_by_key(|group| group.len());
+        if let Some(group) = group {
+            if group.len() >= min_players.max(1) {
+                let sum = group
+                    .iter()
+                    .fold(Vector3::zeros(), |acc, &j| acc + positions[j].coords);
+                res.push((i, Point3::from(sum / group.len() as f32)));
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_game::{HQMGameObject, HQMMessage, HQMServerPlayer};
+
+    // Two red players and a blue one standing apart, the puck at the stick of `owners[i]`
+    // in frame i
+    fn frames(owners: &[usize]) -> Vec<HQMGameState> {
+        let teams = [HQMTeam::Red, HQMTeam::Red, HQMTeam::Blue];
+        let spots: Vec<_> = (0..3)
+            .map(|i| Point3::new(5.0 + 10.0 * i as f32, 0.0, 30.0))
+            .collect();
+        owners
+            .iter()
+            .map(|&owner| {
+                let mut frame = HQMGameState::empty();
+                for (i, team) in teams.into_iter().enumerate() {
+                    frame.player_list[i] = Some(HQMServerPlayer {
+                        name: format!("player {}", i),
+                        team_and_skater: Some((i + 1, team)),
+                    });
+                    frame.objects[i + 1] = HQMGameObject::Player(HQMSkater::at(spots[i]));
+                }
+                frame.objects[0] = HQMGameObject::Puck(HQMPuck::at(spots[owner]));
+                frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn longest_possession_of_each_team() {
+        let owners: Vec<_> = [(0, 10), (2, 30), (1, 20)]
+            .into_iter()
+            .flat_map(|(owner, ticks)| std::iter::repeat_n(owner, ticks))
+            .collect();
+        let frames = frames(&owners);
+        let (red, blue) = longest_possession(&frames, DEFAULT_POSSESSION_THRESHOLD);
+        assert_eq!(
+            red,
+            Some(PossessionStreak {
+                player: 1,
+                team: HQMTeam::Red,
+                start_frame: 40,
+                end_frame: 59,
+                ticks: 20,
+            })
+        );
+        let blue = blue.unwrap();
+        assert_eq!((blue.player, blue.start_frame, blue.ticks), (2, 10, 30));
+    }
+
+    #[test]
+    fn no_streak_for_a_team_without_the_puck() {
+        let frames = frames(&[0; 5]);
+        let (red, blue) = longest_possession(&frames, DEFAULT_POSSESSION_THRESHOLD);
+        assert_eq!(red.map(|x| x.ticks), Some(5));
+        assert_eq!(blue, None);
+    }
+
+    // Red trails 0-1 with nobody near either net from 2:00 left, and `scorer` scores at
+    // tick 400
+    fn late_goal(scorer: HQMTeam) -> Vec<HQMGameState> {
+        let mut frames = frames(&[0; 500]);
+        for (i, frame) in frames.iter_mut().enumerate() {
+            frame.period = 3;
+            frame.time = GOALIE_PULL_LATE_TIME - i as u32;
+            frame.blue_score = 1;
+            if i >= 400 {
+                match scorer {
+                    HQMTeam::Red => frame.red_score += 1,
+                    HQMTeam::Blue => frame.blue_score += 1,
+                }
+            }
+        }
+        frames[400].messages_in_this_packet = vec![HQMMessage::Goal {
+            team: scorer,
+            goal_player_index: None,
+            assist_player_index: None,
+        }];
+        frames
+    }
+
+    #[test]
+    fn goalie_pull_ending_in_a_tying_goal() {
+        let pulls = goalie_pulls(&late_goal(HQMTeam::Red), &RinkGeometry::hqm_default());
+        assert_eq!(
+            pulls,
+            vec![GoaliePull {
+                team: HQMTeam::Red,
+                start_tick: 0,
+                end_tick: 399,
+                resulted_in_goal_for: true,
+                goal_against: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn goalie_pull_with_an_empty_net_goal_against() {
+        let pulls = goalie_pulls(&late_goal(HQMTeam::Blue), &RinkGeometry::hqm_default());
+        assert_eq!(
+            pulls,
+            vec![GoaliePull {
+                team: HQMTeam::Red,
+                start_tick: 0,
+                end_tick: 499,
+                resulted_in_goal_for: false,
+                goal_against: true,
+            }]
+        );
+    }
+}
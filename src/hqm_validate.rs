@@ -0,0 +1,151 @@
+use crate::hqm_game::{frame_pairs, HQMGameState, ObjectRef};
+use crate::hqm_parse::ParseWarning;
+use crate::hqm_rink::RinkGeometry;
+use nalgebra::{Matrix3, Point3};
+use std::fmt;
+
+// Fastest believable movement in meters per second. Anything faster is a teleport.
+const MAX_SKATER_SPEED: f32 = 20.0;
+const MAX_PUCK_SPEED: f32 = 70.0;
+// Largest element of R^T R - I for a rotation matrix to still count as orthonormal
+const ROTATION_TOLERANCE: f32 = 0.01;
+// How far outside the rink an object can be before it is flagged
+const RINK_MARGIN: f32 = 0.5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlausibilityProblem {
+    // Speed in meters per second since the previous frame
+    Teleport { speed: f32 },
+    // Largest element of R^T R - I
+    NonUnitRotation { deviation: f32 },
+    OutsideRink { pos: Point3<f32> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlausibilityIssue {
+    pub frame: usize,
+    pub slot: usize,
+    pub problem: PlausibilityProblem,
+}
+
+impl fmt::Display for PlausibilityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame {} slot {}: ", self.frame, self.slot)?;
+        match &self.problem {
+            PlausibilityProblem::Teleport { speed } => write!(f, "moved at {:.1} m/s", speed),
+            PlausibilityProblem::NonUnitRotation { deviation } => {
+                write!(f, "rotation is off from orthonormal by {}", deviation)
+            }
+            PlausibilityProblem::OutsideRink { pos } => write!(
+                f,
+                "outside the rink at ({:.3}, {:.3}, {:.3})",
+                pos.x, pos.y, pos.z
+            ),
+        }
+    }
+}
+
+fn rotation_deviation(rot: &Matrix3<f32>) -> f32 {
+    (rot.transpose() * rot - Matrix3::identity()).amax()
+}
+
+/// Sanity checks on decoded geometry: objects moving faster than physically possible between
+/// frames, rotation matrices that aren't orthonormal and positions outside the standard rink.
+/// Sticks are not checked, they are allowed to reach over the boards.
+pub fn plausibility_report(frames: &[HQMGameState], tick_rate: f32) -> Vec<PlausibilityIssue> {
+    let rink = RinkGeometry::hqm_default();
+    let mut res = vec![];
+    for (frame, state) in frames.iter().enumerate() {
+        for (slot, object) in state.object_refs() {
+            let pos = *object.pos();
+            let (stick_rot, max_speed) = match object {
+                ObjectRef::Skater(skater) => (Some(&skater.stick_rot), MAX_SKATER_SPEED),
+                ObjectRef::Puck(_) => (None, MAX_PUCK_SPEED),
+            };
+            let mut issue = |problem| {
+                res.push(PlausibilityIssue {
+                    frame,
+                    slot,
+                    problem,
+                })
+            };
+
+            let prev = frame
+                .checked_sub(1)
+                .and_then(|prev| frames[prev].objects.get(slot))
+                .and_then(|prev| prev.pos());
+            if let Some(prev) = prev {
+                let speed = (pos - prev).norm() * tick_rate;
+                if speed > max_speed {
+                    issue(PlausibilityProblem::Teleport { speed });
+                }
+            }
+
+            for rot in std::iter::once(object.rot()).chain(stick_rot) {
+                let deviation = rotation_deviation(rot);
+                if deviation > ROTATION_TOLERANCE {
+                    issue(PlausibilityProblem::NonUnitRotation { deviation });
+                }
+            }
+
+            let outside = pos.x < -RINK_MARGIN
+                || pos.x > rink.width + RINK_MARGIN
+                || pos.z < -RINK_MARGIN
+                || pos.z > rink.length + RINK_MARGIN
+                || pos.y < -RINK_MARGIN;
+            if outside {
+                issue(PlausibilityProblem::OutsideRink { pos });
+            }
+        }
+    }
+    res
+}
+
+/// A warning for every tick where either score is lower than on the tick before.
+pub fn score_regressions(states: &[HQMGameState]) -> Vec<ParseWarning> {
+    frame_pairs(states)
+        .enumerate()
+        .filter(|(_, (prev, cur))| {
+            cur.red_score < prev.red_score || cur.blue_score < prev.blue_score
+        })
+        .map(|(i, (prev, cur))| ParseWarning::ScoreRegression {
+            tick: i + 1,
+            from: (prev.red_score, prev.blue_score),
+            to: (cur.red_score, cur.blue_score),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hqm_replay::parse_replay;
+    use crate::test_util::*;
+
+    #[test]
+    fn score_drop_is_a_regression() {
+        // A desync makes blue's goal disappear for one tick
+        let mut packets = packets(4);
+        for (packet, score) in packets.iter_mut().zip([(0, 0), (1, 1), (1, 0), (1, 1)]) {
+            (packet.red_score, packet.blue_score) = score;
+        }
+        let replay = parse_replay(&replay_bytes(&packets)).unwrap();
+        let expected = vec![ParseWarning::ScoreRegression {
+            tick: 2,
+            from: (1, 1),
+            to: (1, 0),
+        }];
+        assert_eq!(score_regressions(&replay.states), expected);
+        assert_eq!(replay.warnings, expected);
+    }
+
+    #[test]
+    fn rising_scores_are_not_regressions() {
+        let mut packets = packets(3);
+        for (packet, score) in packets.iter_mut().zip([(0, 0), (1, 0), (1, 1)]) {
+            (packet.red_score, packet.blue_score) = score;
+        }
+        let replay = parse_replay(&replay_bytes(&packets)).unwrap();
+        assert!(score_regressions(&replay.states).is_empty());
+    }
+}
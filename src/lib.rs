@@ -0,0 +1,30 @@
+pub mod approx;
+pub mod hqm_compare;
+pub mod hqm_detect;
+pub mod hqm_events;
+pub mod hqm_export;
+pub mod hqm_game;
+pub mod hqm_index;
+pub mod hqm_options;
+pub mod hqm_parse;
+pub mod hqm_print;
+pub mod hqm_replay;
+pub mod hqm_report;
+pub mod hqm_rink;
+pub mod hqm_stats;
+pub mod hqm_validate;
+#[cfg(test)]
+mod test_util;
+
+pub use crate::hqm_game::{
+    format_clock, frame_pairs, frame_timestamp, frame_windows, HQMGameObject, HQMGameState,
+    HQMMessage, HQMPuck, HQMServerPlayer, HQMSkater, HQMTeam, ObjectRef, Scoreboard,
+    DEFAULT_TICK_RATE,
+};
+pub use crate::hqm_options::ParserOptions;
+pub use crate::hqm_parse::{HQMParseError, ParseWarning, PositionClampPolicy};
+pub use crate::hqm_replay::{
+    concat_replays, follow_file, join_replays, parse_all, parse_events_only,
+    parse_events_only_with, parse_file, parse_header, parse_replay, Parser, RawFrame, Replay,
+    ReplayHeader, ReplayParser, StreamingParser,
+};
@@ -0,0 +1,303 @@
+//! Builds replay bytes for the tests, writing the same format the parser reads.
+
+use crate::hqm_game::{HQMMessage, HQMTeam};
+use crate::hqm_parse::{HQMObjectPacket, HQMPuckPacket};
+
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    pub(crate) bytes: Vec<u8>,
+    bit_pos: u8,
+}
+
+impl BitWriter {
+    pub(crate) fn write_bits(&mut self, b: u8, value: u32) {
+        for i in 0..b {
+            if self.bit_pos == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << self.bit_pos;
+            self.bit_pos = (self.bit_pos + 1) % 8;
+        }
+    }
+
+    pub(crate) fn write_u32_aligned(&mut self, value: u32) {
+        self.bit_pos = 0;
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    // Absolute position encoding
+    pub(crate) fn write_pos(&mut self, b: u8, value: u32) {
+        self.write_bits(2, 3);
+        self.write_bits(b, value);
+    }
+
+    // Delta encoding with the smallest width that fits
+    pub(crate) fn write_pos_delta(&mut self, diff: i32) {
+        let (pos_type, b) = match diff {
+            -4..=3 => (0, 3),
+            -32..=31 => (1, 6),
+            _ => (2, 12),
+        };
+        self.write_bits(2, pos_type);
+        self.write_bits(b, diff as u32 & ((1 << b) - 1));
+    }
+
+    // Ends a packet the way the recorder does: the next packet starts after the byte holding
+    // the last bit, or after a whole unused byte if the packet ended on a byte boundary
+    pub(crate) fn end_packet(&mut self) {
+        if self.bit_pos == 0 {
+            self.bytes.push(0);
+        }
+        self.bit_pos = 0;
+    }
+}
+
+pub(crate) struct TestPacket {
+    pub(crate) packet_number: u32,
+    pub(crate) game_over: bool,
+    pub(crate) red_score: u32,
+    pub(crate) blue_score: u32,
+    pub(crate) time: u32,
+    pub(crate) goal_message_timer: u32,
+    pub(crate) period: u32,
+    // Empty slots up to 32
+    pub(crate) objects: Vec<HQMObjectPacket>,
+    // Earlier packet to delta code the objects against, None writes absolute positions
+    pub(crate) reference: Option<u32>,
+    pub(crate) msg_pos: u32,
+    pub(crate) messages: Vec<HQMMessage>,
+}
+
+impl TestPacket {
+    pub(crate) fn new(packet_number: u32) -> Self {
+        TestPacket {
+            packet_number,
+            game_over: false,
+            red_score: 0,
+            blue_score: 0,
+            time: 30000,
+            goal_message_timer: 0,
+            period: 1,
+            objects: vec![],
+            reference: None,
+            msg_pos: 0,
+            messages: vec![],
+        }
+    }
+
+    pub(crate) fn write_scoreboard(&self, w: &mut BitWriter) {
+        w.write_bits(8, 5);
+        w.write_bits(1, self.game_over as u32);
+        w.write_bits(8, self.red_score);
+        w.write_bits(8, self.blue_score);
+        w.write_bits(16, self.time);
+        w.write_bits(16, self.goal_message_timer);
+        w.write_bits(8, self.period);
+        w.write_u32_aligned(self.packet_number);
+        w.write_u32_aligned(self.reference.unwrap_or(self.packet_number.wrapping_sub(1)));
+    }
+
+    pub(crate) fn write_messages(&self, w: &mut BitWriter) {
+        w.write_bits(16, self.messages.len() as u32);
+        w.write_bits(16, self.msg_pos);
+        for msg in self.messages.iter() {
+            write_message(w, msg);
+        }
+    }
+
+    // `reference` is the packet the objects are delta coded against
+    pub(crate) fn write(&self, w: &mut BitWriter, reference: Option<&TestPacket>) {
+        self.write_scoreboard(w);
+        for i in 0..32 {
+            let old = reference.and_then(|x| x.objects.get(i));
+            write_object(
+                w,
+                self.objects.get(i).unwrap_or(&HQMObjectPacket::None),
+                old,
+            );
+        }
+        self.write_messages(w);
+        w.end_packet();
+    }
+}
+
+// The values of an object in the order they are written, with their widths
+fn object_values(object: &HQMObjectPacket) -> Vec<(u8, u32)> {
+    match object {
+        HQMObjectPacket::None => vec![],
+        HQMObjectPacket::Puck(puck) => vec![
+            (17, puck.pos.0),
+            (17, puck.pos.1),
+            (17, puck.pos.2),
+            (31, puck.rot.0),
+            (31, puck.rot.1),
+        ],
+        HQMObjectPacket::Skater(skater) => vec![
+            (17, skater.pos.0),
+            (17, skater.pos.1),
+            (17, skater.pos.2),
+            (31, skater.rot.0),
+            (31, skater.rot.1),
+            (13, skater.stick_pos.0),
+            (13, skater.stick_pos.1),
+            (13, skater.stick_pos.2),
+            (25, skater.stick_rot.0),
+            (25, skater.stick_rot.1),
+            (16, skater.body_turn),
+            (16, skater.body_lean),
+        ],
+    }
+}
+
+// Delta coded against `old` if it is the same kind of object and the changes are small enough
+fn write_object(w: &mut BitWriter, object: &HQMObjectPacket, old: Option<&HQMObjectPacket>) {
+    let object_type = match object {
+        HQMObjectPacket::None => return w.write_bits(1, 0),
+        HQMObjectPacket::Skater(_) => 0,
+        HQMObjectPacket::Puck(_) => 1,
+    };
+    w.write_bits(1, 1);
+    w.write_bits(2, object_type);
+    let old_values = match (object, old) {
+        (HQMObjectPacket::Skater(_), Some(old @ HQMObjectPacket::Skater(_)))
+        | (HQMObjectPacket::Puck(_), Some(old @ HQMObjectPacket::Puck(_))) => object_values(old),
+        _ => vec![],
+    };
+    for (i, (b, v)) in object_values(object).into_iter().enumerate() {
+        match old_values.get(i) {
+            Some((_, old)) if (-2048..=2047).contains(&(v as i32 - *old as i32)) => {
+                w.write_pos_delta(v as i32 - *old as i32)
+            }
+            _ => w.write_pos(b, v),
+        }
+    }
+}
+
+fn write_index(w: &mut BitWriter, index: Option<usize>) {
+    w.write_bits(6, index.map_or(0x3F, |x| x as u32));
+}
+
+pub(crate) fn write_message(w: &mut BitWriter, msg: &HQMMessage) {
+    match msg {
+        HQMMessage::PlayerUpdate {
+            player_name,
+            object,
+            player_index,
+            in_server,
+        } => {
+            w.write_bits(6, 0);
+            w.write_bits(6, *player_index as u32);
+            w.write_bits(1, *in_server as u32);
+            w.write_bits(
+                2,
+                match object {
+                    Some((_, HQMTeam::Red)) => 0,
+                    Some((_, HQMTeam::Blue)) => 1,
+                    None => 2,
+                },
+            );
+            write_index(w, object.map(|(x, _)| x));
+            let mut name = player_name.bytes().collect::<Vec<_>>();
+            name.resize(31, 0);
+            for c in name {
+                w.write_bits(7, c as u32);
+            }
+        }
+        HQMMessage::Goal {
+            team,
+            goal_player_index,
+            assist_player_index,
+        } => {
+            w.write_bits(6, 1);
+            w.write_bits(2, if *team == HQMTeam::Red { 0 } else { 1 });
+            write_index(w, *goal_player_index);
+            write_index(w, *assist_player_index);
+        }
+        HQMMessage::Chat {
+            player_index,
+            message,
+        } => {
+            w.write_bits(6, 2);
+            write_index(w, *player_index);
+            w.write_bits(6, message.len() as u32);
+            for c in message.bytes() {
+                w.write_bits(7, c as u32);
+            }
+        }
+        HQMMessage::Unknown {
+            message_type,
+            raw_bits,
+        } => {
+            w.write_bits(6, *message_type);
+            for b in raw_bits {
+                w.write_bits(8, *b as u32);
+            }
+        }
+    }
+}
+
+/// The standard header followed by the packets, with the correct data length.
+pub(crate) fn replay_bytes(packets: &[TestPacket]) -> Vec<u8> {
+    let mut w = BitWriter::default();
+    for packet in packets {
+        let reference = packet
+            .reference
+            .and_then(|x| packets.iter().find(|p| p.packet_number == x));
+        packet.write(&mut w, reference);
+    }
+    let mut res = vec![0; 4];
+    res.extend((w.bytes.len() as u32).to_le_bytes());
+    res.extend(w.bytes);
+    res
+}
+
+/// `n` packets of an empty rink in period 1, with the clock running from 5:00.
+pub(crate) fn packets(n: u32) -> Vec<TestPacket> {
+    (0..n)
+        .map(|i| {
+            let mut packet = TestPacket::new(i);
+            packet.time = 30000 - i;
+            packet
+        })
+        .collect()
+}
+
+pub(crate) fn puck_packet(x: f32, y: f32, z: f32) -> HQMObjectPacket {
+    HQMObjectPacket::Puck(HQMPuckPacket {
+        pos: (
+            (x * 1024.0) as u32,
+            (y * 1024.0) as u32,
+            (z * 1024.0) as u32,
+        ),
+        rot: (0, 0),
+    })
+}
+
+pub(crate) fn goal(team: HQMTeam, scorer: Option<usize>, assist: Option<usize>) -> HQMMessage {
+    HQMMessage::Goal {
+        team,
+        goal_player_index: scorer,
+        assist_player_index: assist,
+    }
+}
+
+pub(crate) fn chat(player_index: Option<usize>, message: &str) -> HQMMessage {
+    HQMMessage::Chat {
+        player_index,
+        message: message.to_string(),
+    }
+}
+
+pub(crate) fn join(
+    player_index: usize,
+    name: &str,
+    object: Option<(usize, HQMTeam)>,
+) -> HQMMessage {
+    HQMMessage::PlayerUpdate {
+        player_name: name.to_string(),
+        object,
+        player_index,
+        in_server: true,
+    }
+}